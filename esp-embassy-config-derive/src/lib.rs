@@ -0,0 +1,383 @@
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::{
+    Data, DeriveInput, Error, Expr, Fields, GenericArgument, Ident, LitStr, PathArguments, Type,
+    parse_macro_input, punctuated::Punctuated, spanned::Spanned,
+};
+
+/**
+ * Derives `esp_embassy_config::declare::Config` for a struct, with an entry for each
+ * field. See the trait for the types and attributes of the fields.
+ */
+#[proc_macro_derive(Config, attributes(config))]
+pub fn derive_config(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(&input)
+        .unwrap_or_else(|err| err.to_compile_error())
+        .into()
+}
+
+/**
+ * Kind of entry a field is stored in, found from its type.
+ */
+enum Kind {
+    /** `heapless::String<N>`, with the N. */
+    Text(Expr),
+    /** `heapless::Vec<u8, N>`, with the N. */
+    Bytes(Expr),
+    Unsigned(Ident),
+    Signed(Ident),
+    F32,
+    Bool,
+    Ipv4,
+    /** `usize`, the index of one of the choices. */
+    Choice,
+}
+
+/**
+ * Field of the struct, with the settings of its `config` attribute.
+ */
+struct Field {
+    ident: Ident,
+    kind: Kind,
+    name: LitStr,
+    question: LitStr,
+    max_len: Option<Expr>,
+    min: Option<Expr>,
+    max: Option<Expr>,
+    default: Option<LitStr>,
+    secret: bool,
+    choices: Vec<LitStr>,
+}
+
+fn expand(input: &DeriveInput) -> Result<TokenStream2, Error> {
+    let Data::Struct(data) = &input.data else {
+        return Err(Error::new(
+            input.span(),
+            "Config can only be derived for structs",
+        ));
+    };
+    let Fields::Named(named) = &data.fields else {
+        return Err(Error::new(
+            input.span(),
+            "Config can only be derived for structs with named fields",
+        ));
+    };
+    let fields = named
+        .named
+        .iter()
+        .map(parse_field)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let krate = quote!(::esp_embassy_config);
+    let entries = fields.iter().map(|field| entry(field, &krate));
+    let loads = fields.iter().map(|field| load(field, &krate));
+    let (texts, values): (Vec<_>, Vec<_>) = fields.iter().map(|field| save(field, &krate)).unzip();
+
+    let ident = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics #krate::declare::Config for #ident #ty_generics #where_clause {
+            const ENTRIES: &'static [#krate::configs::ConfigEntry<'static>] = &[#(#entries),*];
+
+            async fn load_async<S, C, M>(
+                menu: &mut #krate::configs::ConfigMenu<'_, S, C, M>,
+            ) -> Result<Self, #krate::configs::ConfigError<S::Error>>
+            where
+                S: #krate::declare::__private::ErrorType,
+                C: #krate::cipher::BlockCipher,
+                M: #krate::storage::Access<S>,
+            {
+                Ok(Self { #(#loads),* })
+            }
+
+            async fn save_async<S, C, M>(
+                &self,
+                menu: &mut #krate::configs::ConfigMenu<'_, S, C, M>,
+            ) -> Result<(), #krate::configs::ConfigError<S::Error>>
+            where
+                S: #krate::declare::__private::ErrorType,
+                C: #krate::cipher::BlockCipher,
+                M: #krate::storage::Access<S>,
+            {
+                #(#texts)*
+                menu.store_values_async(&[#(#values),*]).await
+            }
+        }
+
+        // mistakes in the entries, like duplicate names, fail the build
+        const _: () = if let Err(err) = #krate::configs::check_layout(
+            <#ident as #krate::declare::Config>::ENTRIES,
+        ) {
+            panic!("{}", err.message())
+        };
+    })
+}
+
+fn parse_field(field: &syn::Field) -> Result<Field, Error> {
+    let ident = field.ident.clone().expect("named field");
+    let mut parsed = Field {
+        kind: kind(&field.ty)?,
+        name: LitStr::new(&ident.to_string(), ident.span()),
+        question: doc_comment(field)
+            .unwrap_or_else(|| LitStr::new(&ident.to_string(), ident.span())),
+        ident,
+        max_len: None,
+        min: None,
+        max: None,
+        default: None,
+        secret: false,
+        choices: Vec::new(),
+    };
+
+    for attr in field
+        .attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("config"))
+    {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("secret") {
+                parsed.secret = true;
+            } else if meta.path.is_ident("name") {
+                parsed.name = meta.value()?.parse()?;
+            } else if meta.path.is_ident("question") {
+                parsed.question = meta.value()?.parse()?;
+            } else if meta.path.is_ident("default") {
+                parsed.default = Some(meta.value()?.parse()?);
+            } else if meta.path.is_ident("max_len") {
+                parsed.max_len = Some(meta.value()?.parse()?);
+            } else if meta.path.is_ident("min") {
+                parsed.min = Some(meta.value()?.parse()?);
+            } else if meta.path.is_ident("max") {
+                parsed.max = Some(meta.value()?.parse()?);
+            } else if meta.path.is_ident("choices") {
+                let content;
+                syn::parenthesized!(content in meta.input);
+                let choices = Punctuated::<LitStr, syn::Token![,]>::parse_terminated(&content)?;
+                parsed.choices = choices.into_iter().collect();
+            } else {
+                return Err(meta.error("unknown config attribute"));
+            }
+            Ok(())
+        })?;
+    }
+
+    match parsed.kind {
+        Kind::Choice if parsed.choices.is_empty() => Err(Error::new(
+            field.ty.span(),
+            "usize fields need choices(\"a\", \"b\", ...)",
+        )),
+        Kind::Choice => Ok(parsed),
+        _ if !parsed.choices.is_empty() => Err(Error::new(
+            field.ty.span(),
+            "choices can only be used for usize fields",
+        )),
+        _ => Ok(parsed),
+    }
+}
+
+/**
+ * Finds the kind of entry from the type of the field.
+ */
+fn kind(ty: &Type) -> Result<Kind, Error> {
+    let unsupported = || {
+        Error::new(
+            ty.span(),
+            "unsupported config field type, use heapless::String<N>, heapless::Vec<u8, N>, \
+             u8, u16, u32, i8, i16, i32, f32, bool, Ipv4Addr, or usize with choices",
+        )
+    };
+    let Type::Path(path) = ty else {
+        return Err(unsupported());
+    };
+    let segment = path.path.segments.last().ok_or_else(unsupported)?;
+    let args: Vec<&GenericArgument> = match &segment.arguments {
+        PathArguments::AngleBracketed(args) => args.args.iter().collect(),
+        _ => Vec::new(),
+    };
+    let ident = &segment.ident;
+    let kind = match (ident.to_string().as_str(), args.as_slice()) {
+        ("String", [GenericArgument::Const(len)]) => Kind::Text(len.clone()),
+        ("String", [GenericArgument::Type(Type::Path(len))]) => {
+            Kind::Text(Expr::Verbatim(quote!(#len)))
+        }
+        ("Vec", [GenericArgument::Type(byte), len]) if quote!(#byte).to_string() == "u8" => {
+            match len {
+                GenericArgument::Const(len) => Kind::Bytes(len.clone()),
+                GenericArgument::Type(Type::Path(len)) => Kind::Bytes(Expr::Verbatim(quote!(#len))),
+                _ => return Err(unsupported()),
+            }
+        }
+        ("u8" | "u16" | "u32", []) => Kind::Unsigned(ident.clone()),
+        ("i8" | "i16" | "i32", []) => Kind::Signed(ident.clone()),
+        ("f32", []) => Kind::F32,
+        ("bool", []) => Kind::Bool,
+        ("Ipv4Addr", []) => Kind::Ipv4,
+        ("usize", []) => Kind::Choice,
+        _ => return Err(unsupported()),
+    };
+    Ok(kind)
+}
+
+/**
+ * The doc comment of the field, used as the question when none is given.
+ */
+fn doc_comment(field: &syn::Field) -> Option<LitStr> {
+    let lines: Vec<String> = field
+        .attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("doc"))
+        .filter_map(|attr| match &attr.meta.require_name_value().ok()?.value {
+            Expr::Lit(syn::ExprLit {
+                lit: syn::Lit::Str(doc),
+                ..
+            }) => Some(doc.value()),
+            _ => None,
+        })
+        .flat_map(|doc| {
+            doc.lines()
+                .map(|line| line.trim().trim_start_matches('*').trim().to_string())
+                .collect::<Vec<_>>()
+        })
+        .filter(|line| !line.is_empty())
+        .collect();
+    if lines.is_empty() {
+        return None;
+    }
+    Some(LitStr::new(&lines.join(" "), field.span()))
+}
+
+/**
+ * The `ConfigEntry` of the field.
+ */
+fn entry(field: &Field, krate: &TokenStream2) -> TokenStream2 {
+    let Field {
+        name,
+        question,
+        secret,
+        ..
+    } = field;
+    let max_len = |len: TokenStream2| match &field.max_len {
+        Some(max_len) => quote!(#max_len),
+        None => len,
+    };
+    let min_max = |min: TokenStream2, max: TokenStream2| {
+        let min = field.min.as_ref().map_or(min, |min| quote!(#min));
+        let max = field.max.as_ref().map_or(max, |max| quote!(#max));
+        (min, max)
+    };
+    let kind = |kind: TokenStream2| quote!(.with_kind(#krate::value::EntryKind::#kind));
+
+    let (constructor, len, with_kind) = match &field.kind {
+        Kind::Text(len) => (quote!(new), max_len(quote!(#len)), quote!()),
+        Kind::Bytes(len) => (quote!(bytes), max_len(quote!(#len)), quote!()),
+        Kind::Unsigned(ty) => {
+            let (min, max) = min_max(quote!(0), quote!(#ty::MAX as u32));
+            (
+                quote!(new),
+                max_len(quote!(16)),
+                kind(quote!(U32 { min: #min, max: #max })),
+            )
+        }
+        Kind::Signed(ty) => {
+            let (min, max) = min_max(quote!(#ty::MIN as i32), quote!(#ty::MAX as i32));
+            (
+                quote!(new),
+                max_len(quote!(16)),
+                kind(quote!(I32 { min: #min, max: #max })),
+            )
+        }
+        Kind::F32 => {
+            let (min, max) = min_max(quote!(f32::MIN), quote!(f32::MAX));
+            (
+                quote!(new),
+                max_len(quote!(#krate::value::MAX_TEXT_LEN)),
+                kind(quote!(F32 { min: #min, max: #max })),
+            )
+        }
+        Kind::Bool => (quote!(new), max_len(quote!(16)), kind(quote!(Bool))),
+        Kind::Ipv4 => (quote!(new), max_len(quote!(16)), kind(quote!(Ipv4))),
+        Kind::Choice => {
+            let choices = &field.choices;
+            let longest = choices.iter().map(|choice| choice.value().len()).max();
+            let len = longest.unwrap_or(0);
+            (
+                quote!(new),
+                max_len(quote!(#len)),
+                kind(quote!(Choice(&[#(#choices),*]))),
+            )
+        }
+    };
+    let with_default = field
+        .default
+        .as_ref()
+        .map(|default| quote!(.with_default(#default)));
+    quote! {
+        #krate::configs::ConfigEntry::#constructor(#name, #len, #question, #secret)
+            #with_kind
+            #with_default
+    }
+}
+
+/**
+ * Reads the field, in the struct literal made by `load_async`.
+ */
+fn load(field: &Field, krate: &TokenStream2) -> TokenStream2 {
+    let Field { ident, name, .. } = field;
+    let value = match &field.kind {
+        Kind::Text(_) => quote! {{
+            let mut value = Default::default();
+            menu.read_entry_async(#name, &mut value).await?;
+            value
+        }},
+        Kind::Bytes(_) => quote! {{
+            let mut value = Default::default();
+            menu.read_bytes_vec_async(#name, &mut value).await?;
+            value
+        }},
+        Kind::Unsigned(ty) => quote! {
+            #ty::try_from(menu.get_u32_async(#name).await?)
+                .map_err(|_| #krate::configs::ConfigError::Invalid("out of range"))?
+        },
+        Kind::Signed(ty) => quote! {
+            #ty::try_from(menu.get_i32_async(#name).await?)
+                .map_err(|_| #krate::configs::ConfigError::Invalid("out of range"))?
+        },
+        Kind::F32 => quote!(menu.get_f32_async(#name).await?),
+        Kind::Bool => quote!(menu.get_bool_async(#name).await?),
+        Kind::Ipv4 => quote!(menu.get_ipv4_async(#name).await?),
+        Kind::Choice => quote!(menu.get_choice_async(#name).await?),
+    };
+    quote!(#ident: #value)
+}
+
+/**
+ * Statements making the text the field is stored as, if it is not text already, and
+ * the name and value given to `store_values_async`.
+ */
+fn save(field: &Field, krate: &TokenStream2) -> (TokenStream2, TokenStream2) {
+    let Field { ident, name, .. } = field;
+    let text = format_ident!("__{}", ident);
+    match &field.kind {
+        Kind::Text(_) => (quote!(), quote!((#name, self.#ident.as_bytes()))),
+        Kind::Bytes(_) => (quote!(), quote!((#name, &self.#ident[..]))),
+        Kind::Choice => {
+            let choices = &field.choices;
+            (
+                quote! {
+                    let #text = [#(#choices),*]
+                        .get(self.#ident)
+                        .ok_or(#krate::configs::ConfigError::Invalid("not one of the choices"))?;
+                },
+                quote!((#name, #text.as_bytes())),
+            )
+        }
+        _ => (
+            quote! {
+                let #text = #krate::declare::__private::to_text(&self.#ident)?;
+            },
+            quote!((#name, #text.as_bytes())),
+        ),
+    }
+}
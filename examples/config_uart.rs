@@ -2,14 +2,14 @@
 #![no_main]
 
 use embassy_executor::Spawner;
-use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
-use embassy_sync::mutex::Mutex;
 use embassy_time::{Duration, Timer};
 use esp_backtrace as _;
 use esp_embassy_config::{
     config_init,
-    configs::{ConfigEntry, ConfigMenu},
-    key::make_key,
+    configs::required_len,
+    declare_config,
+    key::{DEFAULT_ITERATIONS, device_salt, make_key},
+    partition::find_config_region,
 };
 use esp_hal::{
     aes::Aes,
@@ -17,13 +17,21 @@ use esp_hal::{
     timer::timg::TimerGroup,
     uart::{Config, Uart},
 };
-use log::info;
-use static_cell::StaticCell;
+use esp_storage::FlashStorage;
+use log::{error, info};
 
 pub const READ_BUF_SIZE: usize = 64;
 
 const KEY: &str = "BNMIKUJYHGFDEWRGYJ";
 
+declare_config! {
+    struct AppConfig {
+        value: Text[16], "What is this value?";
+        long_value: Text[32], "What is this other value?", secret = true;
+        port: U32(1, 65535)[16], "Which port should be used?", default = "80";
+    }
+}
+
 #[esp_hal_embassy::main]
 async fn main(spawner: Spawner) {
     // setup embassy
@@ -34,7 +42,7 @@ async fn main(spawner: Spawner) {
 
     // setup encryption
     let mut sha = Sha::new(peripherals.SHA);
-    let encoded_key = make_key::<16>(&mut sha, KEY);
+    let encoded_key = make_key::<16>(&mut sha, KEY, &device_salt(), DEFAULT_ITERATIONS);
     let aes = Aes::<'static>::new(peripherals.AES);
 
     // setup uart
@@ -48,16 +56,28 @@ async fn main(spawner: Spawner) {
     let (uart_rx, uart_tx) = uart0.split();
     Timer::after(Duration::from_millis(100)).await;
 
-    // setup config menu
-    static ENTRIES: StaticCell<[ConfigEntry; 2]> = StaticCell::new();
-    static CONFIG_MENU: StaticCell<Mutex<CriticalSectionRawMutex, ConfigMenu>> = StaticCell::new();
-    let entries = ENTRIES.init([
-        ConfigEntry::new("value", 16, "What is this value?", false),
-        ConfigEntry::new("long_value", 32, "What is this other value?", true),
-    ]);
-    let config_menu = CONFIG_MENU.init(Mutex::new(ConfigMenu::new(entries, encoded_key, aes)));
+    // setup config menu, formatting the storage if it was written with another key, so
+    // the menu can still be used to enter the values again
+    let mut flash = FlashStorage::new();
+    let region = match find_config_region(&mut flash, "config", required_len(AppConfig::ENTRIES)) {
+        Ok(region) => region,
+        Err(err) => {
+            error!("No config partition: {:?}", err);
+            return;
+        }
+    };
+    let config = match AppConfig::init_or_format(encoded_key, aes, flash, region) {
+        Ok(config) => config,
+        Err(err) => {
+            error!("Can not open the config: {}", err);
+            return;
+        }
+    };
+    if let Ok(port) = config.port.get(&mut *config.menu.lock().await) {
+        info!("Using port {}", port);
+    }
 
     // start config menu
     info!("Starting config menu");
-    config_init(spawner, config_menu, uart_rx, uart_tx).await;
+    config_init(spawner, config.menu, uart_rx, uart_tx).await;
 }
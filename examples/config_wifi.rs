@@ -9,8 +9,9 @@ use esp_alloc as _;
 use esp_backtrace as _;
 use esp_embassy_config::{
     config_init,
-    configs::{ConfigEntry, ConfigMenu},
-    key::make_key,
+    configs::{ConfigEntry, ConfigMenu, required_len},
+    key::{DEFAULT_ITERATIONS, KeySource, PassphraseDeriver},
+    partition::find_config_region,
 };
 use esp_embassy_wifihelper::WifiStack;
 use esp_hal::{
@@ -20,13 +21,14 @@ use esp_hal::{
     timer::timg::TimerGroup,
     uart::{Config, Uart},
 };
+use esp_storage::FlashStorage;
 use esp_wifi::wifi::ClientConfiguration;
-use log::info;
+use log::{error, info};
 use static_cell::StaticCell;
 
 pub const READ_BUF_SIZE: usize = 64;
 
-const STORAGE_KEY: &str = env!("STORAGE_KEY");
+const STORAGE_KEY: Option<&str> = option_env!("STORAGE_KEY");
 
 #[esp_hal_embassy::main]
 async fn main(spawner: Spawner) {
@@ -42,8 +44,15 @@ async fn main(spawner: Spawner) {
 
     // setup encryption
     let mut sha = Sha::new(peripherals.SHA);
-    let encoded_key = make_key::<16>(&mut sha, STORAGE_KEY);
+    // the passphrase is only used if given when building, otherwise the key is made
+    // from the MAC address in the eFuses
+    let key_source = STORAGE_KEY.map_or(KeySource::DeviceMac, KeySource::Passphrase);
+    let encoded_key = key_source.derive::<16>(&mut sha, DEFAULT_ITERATIONS);
     let aes = Aes::<'static>::new(peripherals.AES);
+    // the key can be changed from the menu, to move the stored values to a new
+    // STORAGE_KEY before flashing firmware built with it
+    static KEY_DERIVER: StaticCell<PassphraseDeriver<Sha, 16>> = StaticCell::new();
+    let key_deriver = KEY_DERIVER.init(PassphraseDeriver::new(sha, DEFAULT_ITERATIONS));
 
     // setup uart
     let (tx_pin, rx_pin) = (peripherals.GPIO21, peripherals.GPIO20);
@@ -65,12 +74,31 @@ async fn main(spawner: Spawner) {
     static ENTRIES: StaticCell<[ConfigEntry; 1]> = StaticCell::new();
     static CONFIG_MENU: StaticCell<Mutex<CriticalSectionRawMutex, ConfigMenu>> = StaticCell::new();
     let entries = ENTRIES.init([ConfigEntry::new("test", 32, "Test test?", false)]);
-    let config_menu = CONFIG_MENU.init(Mutex::new(ConfigMenu::new(
+    let mut flash = FlashStorage::new();
+    let region = match find_config_region(&mut flash, "config", required_len(entries)) {
+        Ok(region) => region,
+        Err(err) => {
+            error!("No config partition: {:?}", err);
+            return;
+        }
+    };
+    // values stored with another STORAGE_KEY can not be read, so the storage is formatted
+    // and the values entered again in the menu
+    let config_menu = match ConfigMenu::new_or_format(
         entries,
         encoded_key,
         aes,
+        flash,
+        region,
         wifi_channel.sender(),
-    )));
+    ) {
+        Ok(config_menu) => config_menu,
+        Err(err) => {
+            error!("Can not open the config: {}", err);
+            return;
+        }
+    };
+    let config_menu = CONFIG_MENU.init(Mutex::new(config_menu.with_key_deriver(key_deriver)));
 
     let wifi = WifiStack::new_connect_later(
         spawner,
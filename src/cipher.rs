@@ -0,0 +1,386 @@
+#[cfg(feature = "esp")]
+use esp_hal::aes::{Aes, Key, Mode};
+
+/**
+ * Key for the cipher, either 128 or 256 bits.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CipherKey {
+    Aes128([u8; 16]),
+    Aes256([u8; 32]),
+}
+
+impl CipherKey {
+    /**
+     * Size of the key in bits.
+     */
+    pub fn bits(&self) -> u16 {
+        match self {
+            CipherKey::Aes128(_) => 128,
+            CipherKey::Aes256(_) => 256,
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            CipherKey::Aes128(key) => key,
+            CipherKey::Aes256(key) => key,
+        }
+    }
+}
+
+impl From<[u8; 16]> for CipherKey {
+    fn from(key: [u8; 16]) -> Self {
+        CipherKey::Aes128(key)
+    }
+}
+
+impl From<[u8; 32]> for CipherKey {
+    fn from(key: [u8; 32]) -> Self {
+        CipherKey::Aes256(key)
+    }
+}
+
+/**
+ * A 128 bit block cipher used to encrypt the config before it is written to storage.
+ * The esp32 AES peripheral implements this, and with the `soft-crypto` feature a
+ * software AES is available for hosts and chips without the peripheral.
+ */
+pub trait BlockCipher {
+    fn encrypt_block(&mut self, key: &CipherKey, block: &mut [u8; 16]);
+    fn decrypt_block(&mut self, key: &CipherKey, block: &mut [u8; 16]);
+}
+
+#[cfg(feature = "esp")]
+impl BlockCipher for Aes<'_> {
+    fn encrypt_block(&mut self, key: &CipherKey, block: &mut [u8; 16]) {
+        match *key {
+            CipherKey::Aes128(k) => self.process(block, Mode::Encryption128, Key::from(k)),
+            CipherKey::Aes256(k) => self.process(block, Mode::Encryption256, Key::from(k)),
+        }
+    }
+
+    fn decrypt_block(&mut self, key: &CipherKey, block: &mut [u8; 16]) {
+        match *key {
+            CipherKey::Aes128(k) => self.process(block, Mode::Decryption128, Key::from(k)),
+            CipherKey::Aes256(k) => self.process(block, Mode::Decryption256, Key::from(k)),
+        }
+    }
+}
+
+/**
+ * Passthrough cipher, for tests and values that need no protection. It is not clear text
+ * in storage, but gives neither secrecy nor integrity: with the block operation doing
+ * nothing, GCM XORs the value with the counter blocks made from the public nonce, and the
+ * tag does not depend on the key, so anyone can read or change the stored values.
+ */
+pub struct NoCipher;
+
+impl BlockCipher for NoCipher {
+    fn encrypt_block(&mut self, _key: &CipherKey, _block: &mut [u8; 16]) {}
+
+    fn decrypt_block(&mut self, _key: &CipherKey, _block: &mut [u8; 16]) {}
+}
+
+/**
+ * Software implementation of AES, from the RustCrypto `aes` crate.
+ */
+#[cfg(feature = "soft-crypto")]
+pub struct SoftAes;
+
+#[cfg(feature = "soft-crypto")]
+impl BlockCipher for SoftAes {
+    fn encrypt_block(&mut self, key: &CipherKey, block: &mut [u8; 16]) {
+        use aes::cipher::{BlockEncrypt, KeyInit};
+        match key {
+            CipherKey::Aes128(k) => aes::Aes128::new(k.into()).encrypt_block(block.into()),
+            CipherKey::Aes256(k) => aes::Aes256::new(k.into()).encrypt_block(block.into()),
+        }
+    }
+
+    fn decrypt_block(&mut self, key: &CipherKey, block: &mut [u8; 16]) {
+        use aes::cipher::{BlockDecrypt, KeyInit};
+        match key {
+            CipherKey::Aes128(k) => aes::Aes128::new(k.into()).decrypt_block(block.into()),
+            CipherKey::Aes256(k) => aes::Aes256::new(k.into()).decrypt_block(block.into()),
+        }
+    }
+}
+
+/** Size of the authentication tag stored with each value. */
+pub const TAG_SIZE: usize = 16;
+/** Size of the nonce stored with each value. */
+pub const NONCE_SIZE: usize = 12;
+
+/**
+ * AES-GCM (NIST SP 800-38D) built on a `BlockCipher`, so the AES peripheral can still be
+ * used for the block operations. The data is processed in blocks of 16 bytes, where only
+ * the last one may be shorter. A nonce must never be used twice with the same key.
+ */
+pub struct Gcm {
+    key: CipherKey,
+    h: u128,
+    j0: [u8; 16],
+    blocks: u32,
+    ghash: u128,
+    aad_len: u64,
+    len: u64,
+}
+
+impl Gcm {
+    pub fn new(
+        cipher: &mut impl BlockCipher,
+        key: &CipherKey,
+        nonce: &[u8; NONCE_SIZE],
+        aad: &[u8],
+    ) -> Self {
+        let mut h = [0u8; 16];
+        cipher.encrypt_block(key, &mut h);
+        let mut j0 = [0u8; 16];
+        j0[..NONCE_SIZE].copy_from_slice(nonce);
+        j0[15] = 1;
+
+        let mut gcm = Self {
+            key: *key,
+            h: u128::from_be_bytes(h),
+            j0,
+            blocks: 0,
+            ghash: 0,
+            aad_len: aad.len() as u64,
+            len: 0,
+        };
+        for chunk in aad.chunks(16) {
+            gcm.absorb(chunk);
+        }
+        gcm
+    }
+
+    pub fn encrypt(&mut self, cipher: &mut impl BlockCipher, data: &mut [u8]) {
+        self.apply_keystream(cipher, data);
+        self.absorb(data);
+    }
+
+    pub fn decrypt(&mut self, cipher: &mut impl BlockCipher, data: &mut [u8]) {
+        self.absorb(data);
+        self.apply_keystream(cipher, data);
+    }
+
+    /**
+     * Returns the authentication tag of the associated data and the processed data.
+     */
+    pub fn finalize(mut self, cipher: &mut impl BlockCipher) -> [u8; TAG_SIZE] {
+        let lens = ((self.aad_len * 8) as u128) << 64 | (self.len * 8) as u128;
+        self.ghash = gf_mul(self.ghash ^ lens, self.h);
+
+        let mut tag = self.j0;
+        cipher.encrypt_block(&self.key, &mut tag);
+        (u128::from_be_bytes(tag) ^ self.ghash).to_be_bytes()
+    }
+
+    /**
+     * Checks the tag against the one of the associated data and the processed data. The
+     * whole tag is always compared, so the time taken does not tell how much matched.
+     */
+    pub fn verify(self, cipher: &mut impl BlockCipher, tag: &[u8; TAG_SIZE]) -> bool {
+        let diff = self
+            .finalize(cipher)
+            .iter()
+            .zip(tag)
+            .fold(0, |diff, (a, b)| diff | (a ^ b));
+        diff == 0
+    }
+
+    fn apply_keystream(&mut self, cipher: &mut impl BlockCipher, data: &mut [u8]) {
+        debug_assert!(data.len() <= 16 && self.len.is_multiple_of(16));
+        self.blocks += 1;
+        let mut block = self.j0;
+        let counter = u32::from_be_bytes([block[12], block[13], block[14], block[15]]);
+        block[12..].copy_from_slice(&counter.wrapping_add(self.blocks).to_be_bytes());
+        cipher.encrypt_block(&self.key, &mut block);
+        for (byte, key) in data.iter_mut().zip(block) {
+            *byte ^= key;
+        }
+        self.len += data.len() as u64;
+    }
+
+    /**
+     * Adds a block to the GHASH, padded with zeros.
+     */
+    fn absorb(&mut self, data: &[u8]) {
+        let mut block = [0u8; 16];
+        block[..data.len()].copy_from_slice(data);
+        self.ghash = gf_mul(self.ghash ^ u128::from_be_bytes(block), self.h);
+    }
+}
+
+/**
+ * Multiplication in GF(2^128) with the bit order used by GCM.
+ */
+fn gf_mul(x: u128, y: u128) -> u128 {
+    let mut z = 0;
+    let mut v = y;
+    for i in (0..128).rev() {
+        if (x >> i) & 1 != 0 {
+            z ^= v;
+        }
+        v = if v & 1 != 0 {
+            (v >> 1) ^ (0xe1 << 120)
+        } else {
+            v >> 1
+        };
+    }
+    z
+}
+
+#[cfg(all(test, feature = "soft-crypto"))]
+mod tests {
+    use super::*;
+    use heapless::Vec;
+
+    fn hex(text: &str) -> Vec<u8, 64> {
+        (0..text.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    /**
+     * Encrypts and decrypts with the test case from the GCM specification, which NIST
+     * SP 800-38D refers to, 16 bytes at a time like the config values.
+     */
+    fn check(key: CipherKey, nonce: &str, aad: &str, plain: &str, expected: &str, tag: &str) {
+        let nonce: [u8; NONCE_SIZE] = hex(nonce).as_slice().try_into().unwrap();
+        let aad = hex(aad);
+        let mut data = hex(plain);
+
+        let mut gcm = Gcm::new(&mut SoftAes, &key, &nonce, &aad);
+        for chunk in data.chunks_mut(16) {
+            gcm.encrypt(&mut SoftAes, chunk);
+        }
+        assert_eq!(data, hex(expected));
+        assert_eq!(gcm.finalize(&mut SoftAes)[..], hex(tag));
+
+        let mut tag: [u8; TAG_SIZE] = hex(tag).as_slice().try_into().unwrap();
+        let mut gcm = Gcm::new(&mut SoftAes, &key, &nonce, &aad);
+        for chunk in data.chunks_mut(16) {
+            gcm.decrypt(&mut SoftAes, chunk);
+        }
+        assert_eq!(data, hex(plain));
+        assert!(gcm.verify(&mut SoftAes, &tag));
+
+        tag[TAG_SIZE - 1] ^= 1;
+        let mut gcm = Gcm::new(&mut SoftAes, &key, &nonce, &aad);
+        for chunk in data.chunks_mut(16) {
+            gcm.encrypt(&mut SoftAes, chunk);
+        }
+        assert!(!gcm.verify(&mut SoftAes, &tag));
+    }
+
+    const KEY: &str = "feffe9928665731c6d6a8f9467308308";
+    const NONCE: &str = "cafebabefacedbaddecaf888";
+    const AAD: &str = "feedfacedeadbeeffeedfacedeadbeefabaddad2";
+    const PLAIN: &str = "d9313225f88406e5a55909c5aff5269a86a7a9531534f7da2e4c303d8a318a72\
+                         1c3c0c95956809532fcf0e2449a6b525b16aedf5aa0de657ba637b391aafd255";
+
+    fn key128(key: &str) -> CipherKey {
+        CipherKey::Aes128(hex(key).as_slice().try_into().unwrap())
+    }
+
+    fn key256(key: &str) -> CipherKey {
+        CipherKey::Aes256(hex(key).as_slice().try_into().unwrap())
+    }
+
+    #[test]
+    fn aes128_gcm_empty() {
+        // test case 1
+        check(
+            CipherKey::Aes128([0; 16]),
+            "000000000000000000000000",
+            "",
+            "",
+            "",
+            "58e2fccefa7e3061367f1d57a4e7455a",
+        );
+    }
+
+    #[test]
+    fn aes128_gcm_one_block() {
+        // test case 2
+        check(
+            CipherKey::Aes128([0; 16]),
+            "000000000000000000000000",
+            "",
+            "00000000000000000000000000000000",
+            "0388dace60b6a392f328c2b971b2fe78",
+            "ab6e47d42cec13bdf53a67b21257bddf",
+        );
+    }
+
+    #[test]
+    fn aes128_gcm_without_aad() {
+        // test case 3
+        check(
+            key128(KEY),
+            NONCE,
+            "",
+            PLAIN,
+            "42831ec2217774244b7221b784d0d49ce3aa212f2c02a4e035c17e2329aca12e\
+             21d514b25466931c7d8f6a5aac84aa051ba30b396a0aac973d58e091473f5985",
+            "4d5c2af327cd64a62cf35abd2ba6fab4",
+        );
+    }
+
+    #[test]
+    fn aes128_gcm_partial_blocks() {
+        // test case 4, with 20 bytes of aad and 60 bytes of data
+        check(
+            key128(KEY),
+            NONCE,
+            AAD,
+            &PLAIN[..120],
+            "42831ec2217774244b7221b784d0d49ce3aa212f2c02a4e035c17e2329aca12e\
+             21d514b25466931c7d8f6a5aac84aa051ba30b396a0aac973d58e091",
+            "5bc94fbc3221a5db94fae95ae7121a47",
+        );
+    }
+
+    #[test]
+    fn aes256_gcm_empty() {
+        // test case 13
+        check(
+            CipherKey::Aes256([0; 32]),
+            "000000000000000000000000",
+            "",
+            "",
+            "",
+            "530f8afbc74536b9a963b4f1c4cb738b",
+        );
+    }
+
+    #[test]
+    fn aes256_gcm_one_block() {
+        // test case 14
+        check(
+            CipherKey::Aes256([0; 32]),
+            "000000000000000000000000",
+            "",
+            "00000000000000000000000000000000",
+            "cea7403d4d606b6e074ec5d3baf39d18",
+            "d0d1c8a799996bf0265b98b5d48ab919",
+        );
+    }
+
+    #[test]
+    fn aes256_gcm_partial_blocks() {
+        // test case 16, with 20 bytes of aad and 60 bytes of data
+        check(
+            key256("feffe9928665731c6d6a8f9467308308feffe9928665731c6d6a8f9467308308"),
+            NONCE,
+            AAD,
+            &PLAIN[..120],
+            "522dc1f099567d07f47f37a32a84427d643a8cdcbfe5c0c97598a2bd2555d1aa\
+             8cb08e48590dbb3da7b08b1056828838c5f61e6393ba7a0abcc9f662",
+            "76fc6ece0f4e1768cddf8853bb2d551b",
+        );
+    }
+}
@@ -1,16 +1,45 @@
+use crate::cipher::{BlockCipher, CipherKey, Gcm, NONCE_SIZE, TAG_SIZE};
+use crate::declare::Config;
+use crate::key::KeyDeriver;
+use crate::record_store::{
+    Record, RecordError, RecordStore, RecordWriter, Transaction, max_record_size, record_size,
+};
+use crate::storage::{Access, Async, Blocking, StorageRegion};
+use crate::value::{Constraint, EntryKind, MAX_TEXT_LEN, parse_bool, parse_ipv4};
+use core::net::Ipv4Addr;
+use core::{fmt, iter};
+use embassy_futures::block_on;
 #[cfg(feature = "wifi")]
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 #[cfg(feature = "wifi")]
 use embassy_sync::channel::Sender;
-use embedded_storage::ReadStorage;
-use embedded_storage::Storage;
-use esp_hal::aes::{Aes, Key, Mode};
-use esp_println::println;
+use embedded_storage::nor_flash::{ErrorType, NorFlash};
+use embedded_storage_async::nor_flash::NorFlash as AsyncNorFlash;
+#[cfg(feature = "esp")]
+use esp_hal::aes::Aes;
+#[cfg(feature = "esp")]
+use esp_println::{print, println};
+#[cfg(feature = "esp")]
 use esp_storage::FlashStorage;
 #[cfg(feature = "wifi")]
 use esp_wifi::wifi::ClientConfiguration;
+use log::{error, info};
 
-pub struct ConfigMenu<'a> {
+/**
+ * Menu holding the config entries, and the storage they are written to.
+ * The storage can be any `embedded_storage::nor_flash::NorFlash`, and defaults to the
+ * internal flash of the esp32. The cipher defaults to the esp32 AES peripheral.
+ * With `new_async` the storage is an `embedded_storage_async::nor_flash::NorFlash`,
+ * and the values are read and stored with the `_async` methods.
+ */
+pub struct ConfigMenu<
+    'a,
+    #[cfg(feature = "esp")] S = FlashStorage,
+    #[cfg(not(feature = "esp"))] S,
+    #[cfg(feature = "esp")] C = Aes<'a>,
+    #[cfg(not(feature = "esp"))] C,
+    M = Blocking,
+> {
     pub entries: &'a [ConfigEntry<'a>],
     #[cfg(feature = "wifi")]
     pub wifi_ssid: ConfigEntry<'a>,
@@ -20,40 +49,500 @@ pub struct ConfigMenu<'a> {
     pub wifi_autostart: ConfigEntry<'a>,
     #[cfg(feature = "wifi")]
     pub wifi_sender: Sender<'static, CriticalSectionRawMutex, ClientConfiguration, 1>,
-    key: [u8; 16],
-    aes: Aes<'a>,
-    storage: FlashStorage,
+    key: CipherKey,
+    key_deriver: Option<&'a mut (dyn KeyDeriver + Send)>,
+    cipher: C,
+    store: RecordStore<S, M>,
 }
 
-impl<'a> ConfigMenu<'a> {
+/** Id of the record holding the header of the config storage. */
+const HEADER_ID: u32 = 0xffff_ff00;
+
+/**
+ * Header of the config storage, stored in clear text as its own record, so a storage
+ * written by another firmware, or with another key, is detected when booting.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Header {
+    version: u16,
+    key_bits: u16,
+    /** Start of a block encrypted with the key, to tell if the key is right. */
+    key_check: [u8; 8],
+    /** Hash of the names and sizes of the entries. */
+    schema: u32,
+}
+
+impl Header {
+    const SIZE: usize = 20;
+    const MAGIC: [u8; 4] = *b"ECFG";
+    const VERSION: u16 = 2;
+    /**
+     * Block encrypted for the key check value. It ends with a zero counter, so it is
+     * never one of the counter blocks encrypted by GCM, and is not the zero block the
+     * GCM hash key is made from.
+     */
+    const KEY_CHECK_BLOCK: [u8; 16] = *b"key check\0\0\0\0\0\0\0";
+
+    fn new(key: &CipherKey, cipher: &mut impl BlockCipher, schema: u32) -> Self {
+        let mut block = Self::KEY_CHECK_BLOCK;
+        cipher.encrypt_block(key, &mut block);
+        let mut key_check = [0u8; 8];
+        key_check.copy_from_slice(&block[..8]);
+        Self {
+            version: Self::VERSION,
+            key_bits: key.bits(),
+            key_check,
+            schema,
+        }
+    }
+
+    fn to_bytes(self) -> [u8; Self::SIZE] {
+        let mut bytes = [0u8; Self::SIZE];
+        bytes[0..4].copy_from_slice(&Self::MAGIC);
+        bytes[4..6].copy_from_slice(&self.version.to_le_bytes());
+        bytes[6..8].copy_from_slice(&self.key_bits.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.key_check);
+        bytes[16..20].copy_from_slice(&self.schema.to_le_bytes());
+        bytes
+    }
+
+    /**
+     * Parses the header, or gives `None` if it does not start with the magic.
+     */
+    fn from_bytes(bytes: &[u8; Self::SIZE]) -> Option<Self> {
+        if bytes[0..4] != Self::MAGIC {
+            return None;
+        }
+        let mut key_check = [0u8; 8];
+        key_check.copy_from_slice(&bytes[8..16]);
+        Some(Self {
+            version: u16::from_le_bytes([bytes[4], bytes[5]]),
+            key_bits: u16::from_le_bytes([bytes[6], bytes[7]]),
+            key_check,
+            schema: u32::from_le_bytes([bytes[16], bytes[17], bytes[18], bytes[19]]),
+        })
+    }
+}
+
+impl<'a, S: NorFlash, C: BlockCipher> ConfigMenu<'a, S, C> {
+    /**
+     * Creates the menu, with the entries stored as records in the given storage region.
+     * Fails if the entries do not fit in the region, the region is outside the storage,
+     * or the region is not aligned to the erase size of the storage. If the records in
+     * the region are corrupt, the region is formatted. The key can be 128 or 256 bits.
+     * Fails if the storage was written with another key, or by a newer format version,
+     * so the values are kept for the right key or firmware, and `new_or_format` gives
+     * them up instead. The values are migrated if the entries have changed since they
+     * were written.
+     */
     pub fn new(
-        values: &'a mut [ConfigEntry<'a>],
-        key: [u8; 16],
-        aes: Aes<'a>,
+        values: &'a [ConfigEntry<'a>],
+        key: impl Into<CipherKey>,
+        cipher: C,
+        storage: S,
+        region: StorageRegion,
         #[cfg(feature = "wifi")] wifi_sender: Sender<
             'static,
             CriticalSectionRawMutex,
             ClientConfiguration,
             1,
         >,
-    ) -> Self {
-        let mut offset = 0;
-        for value in values.iter_mut() {
-            value.offset = offset;
-            offset += 16 * (value.n_blocks as u32);
-        }
-
-        let mut wifi_ssid = ConfigEntry::new("wifi_ssid", 32, "Wifi SSID", false);
-        wifi_ssid.offset = offset;
-        let mut wifi_pass = ConfigEntry::new("wifi_pass", 64, "Wifi Password", true);
-        wifi_pass.offset = offset + 32;
-        let mut wifi_autostart = ConfigEntry::new(
-            "wifi_autostart",
-            32,
-            "Set to 'yes' if wifi should be connected automatically at boot",
-            false,
-        );
-        wifi_autostart.offset = offset + 32 + 64;
+    ) -> Result<Self, ConfigError<S::Error>> {
+        let key = key.into();
+        #[cfg(feature = "wifi")]
+        let menu = Self::create(values, key, cipher, storage, region, false, wifi_sender);
+        #[cfg(not(feature = "wifi"))]
+        let menu = Self::create(values, key, cipher, storage, region, false);
+        block_on(menu)
+    }
+
+    /**
+     * Creates the menu like `new`, but formats the storage instead of failing when it was
+     * written with another key, by a newer format version, or has a corrupt header. The
+     * stored values are lost, but the device boots, and the values can be entered again
+     * in the menu. Used when the values are not worth more than a working device.
+     */
+    pub fn new_or_format(
+        values: &'a [ConfigEntry<'a>],
+        key: impl Into<CipherKey>,
+        cipher: C,
+        storage: S,
+        region: StorageRegion,
+        #[cfg(feature = "wifi")] wifi_sender: Sender<
+            'static,
+            CriticalSectionRawMutex,
+            ClientConfiguration,
+            1,
+        >,
+    ) -> Result<Self, ConfigError<S::Error>> {
+        let key = key.into();
+        #[cfg(feature = "wifi")]
+        let menu = Self::create(values, key, cipher, storage, region, true, wifi_sender);
+        #[cfg(not(feature = "wifi"))]
+        let menu = Self::create(values, key, cipher, storage, region, true);
+        block_on(menu)
+    }
+
+    /**
+     * Changes the key from passphrases, with the key deriver set by `with_key_deriver`.
+     * Fails with `ConfigError::NoKeyDeriver` if there is none.
+     */
+    pub fn rekey_passphrase(&mut self, old: &str, new: &str) -> Result<(), ConfigError<S::Error>> {
+        block_on(self.rekey_passphrase_async(old, new))
+    }
+
+    /**
+     * Number of times the sector has been erased, to keep an eye on the flash wear.
+     */
+    pub fn erase_count(&mut self, sector: u32) -> Result<u32, ConfigError<S::Error>> {
+        block_on(self.erase_count_async(sector))
+    }
+
+    /**
+     * Erases all stored values.
+     */
+    pub fn reset(&mut self) -> Result<(), ConfigError<S::Error>> {
+        block_on(self.reset_async())
+    }
+
+    /**
+     * Stores the value, if it is valid for the entry.
+     */
+    pub fn store_entry(&mut self, name: &str, input: &str) -> Result<(), ConfigError<S::Error>> {
+        block_on(self.store_entry_async(name, input))
+    }
+
+    /**
+     * Stores several values as one transaction, so either all or none of them are
+     * changed if the power is lost while writing, e.g. for the wifi ssid and password.
+     * Nothing is written if an entry is unknown or a value is not valid.
+     */
+    pub fn store_entries(&mut self, values: &[(&str, &str)]) -> Result<(), ConfigError<S::Error>> {
+        block_on(self.store_entries_async(values))
+    }
+
+    /**
+     * Stores several values as one transaction, like `store_entries`, where the values
+     * can be binary. The values of text entries are the bytes of the text.
+     */
+    pub fn store_values(&mut self, values: &[(&str, &[u8])]) -> Result<(), ConfigError<S::Error>> {
+        block_on(self.store_values_async(values))
+    }
+
+    /**
+     * Stores a binary value in an entry made with `ConfigEntry::bytes`.
+     */
+    pub fn store_bytes(&mut self, name: &str, value: &[u8]) -> Result<(), ConfigError<S::Error>> {
+        block_on(self.store_bytes_async(name, value))
+    }
+
+    /**
+     * Starts writing a value in parts, for values too large to have in memory, like a
+     * certificate. The parts are written with `write_value`, and the value is stored
+     * when `finish_value` is called. Only text and binary entries can be written like
+     * this, and not entries with a `Constraint::Pattern` or `Constraint::Custom`, since
+     * those need the whole value.
+     *
+     * The value is written to the storage as the parts come, in its own transaction, so
+     * other values should not be stored before it is finished. Storing another value
+     * interrupts it, and `ConfigError::Interrupted` is given for the rest of the parts.
+     * The previous value is kept if the value is never finished.
+     */
+    pub fn begin_value(&mut self, name: &str) -> Result<ValueWriter<'a>, ConfigError<S::Error>> {
+        block_on(self.begin_value_async(name))
+    }
+
+    /**
+     * Writes the next part of a value started with `begin_value`. Text values have to
+     * be ASCII, and are checked against the characters allowed by the constraints.
+     */
+    pub fn write_value(
+        &mut self,
+        value: &mut ValueWriter<'a>,
+        bytes: &[u8],
+    ) -> Result<(), ConfigError<S::Error>> {
+        block_on(self.write_value_async(value, bytes))
+    }
+
+    /**
+     * Stores a value written with `write_value`, after checking its length against
+     * the constraints. Nothing is stored if it fails.
+     */
+    pub fn finish_value(&mut self, value: ValueWriter<'a>) -> Result<(), ConfigError<S::Error>> {
+        block_on(self.finish_value_async(value))
+    }
+
+    /**
+     * Reads the binary value of an entry made with `ConfigEntry::bytes` into the output,
+     * and gives the length of it. The default is read like for `read_entry`.
+     */
+    pub fn read_bytes(
+        &mut self,
+        name: &str,
+        output: &mut [u8],
+    ) -> Result<usize, ConfigError<S::Error>> {
+        block_on(self.read_bytes_async(name, output))
+    }
+
+    /**
+     * Reads the binary value of an entry made with `ConfigEntry::bytes`.
+     */
+    pub fn read_bytes_vec<const N: usize>(
+        &mut self,
+        name: &str,
+        output: &mut heapless::Vec<u8, N>,
+    ) -> Result<(), ConfigError<S::Error>> {
+        block_on(self.read_bytes_vec_async(name, output))
+    }
+
+    /**
+     * Changes the key the values are encrypted with. Every stored value, including the
+     * wifi entries, is decrypted with the old key and written again encrypted with the
+     * new one, in a single transaction, so if the power is lost all values are still
+     * under the old key. The new key can be of another size than the old one.
+     *
+     * The old key has to be the key the config was opened with, and all values are checked
+     * against it before anything is written, so nothing is changed if the old key is
+     * wrong, or a value can not be read. The error tells why, `ConfigError::DecryptFailed`
+     * for a wrong old key. The region needs room for a second copy of all the values while
+     * the transaction is written.
+     */
+    pub fn rekey(
+        &mut self,
+        old_key: impl Into<CipherKey>,
+        new_key: impl Into<CipherKey>,
+    ) -> Result<(), ConfigError<S::Error>> {
+        block_on(self.rekey_async(old_key, new_key))
+    }
+
+    /**
+     * Reads the value of the entry. If the entry has a default, it is read instead when
+     * the entry has never been written, or the stored value is corrupt or was written
+     * with another key.
+     */
+    pub fn read_entry<const MAX_SZ: usize>(
+        &mut self,
+        name: &str,
+        output: &mut heapless::String<MAX_SZ>,
+    ) -> Result<(), ConfigError<S::Error>> {
+        block_on(self.read_entry_async(name, output))
+    }
+
+    /**
+     * Reads the value of the entry in parts, calling `f` with each part, so the value
+     * does not have to fit in memory. Gives the length of the value. The default is
+     * read like for `read_entry`, and is given as one part.
+     */
+    pub fn read_entry_with(
+        &mut self,
+        name: &str,
+        f: impl FnMut(&[u8]),
+    ) -> Result<usize, ConfigError<S::Error>> {
+        block_on(self.read_entry_with_async(name, f))
+    }
+
+    /**
+     * Reads a whole config struct, see `declare::Config`.
+     */
+    pub fn load<T: Config>(&mut self) -> Result<T, ConfigError<S::Error>> {
+        T::load(self)
+    }
+
+    /**
+     * Stores a whole config struct in one transaction, see `declare::Config`.
+     */
+    pub fn save<T: Config>(&mut self, config: &T) -> Result<(), ConfigError<S::Error>> {
+        config.save(self)
+    }
+
+    /**
+     * Restores the default of the entry, by removing the stored value. Entries without
+     * a default are not set afterwards.
+     */
+    pub fn restore_default(&mut self, name: &str) -> Result<(), ConfigError<S::Error>> {
+        block_on(self.restore_default_async(name))
+    }
+
+    /**
+     * Restores the defaults of all entries, including the wifi entries, in one
+     * transaction.
+     */
+    pub fn restore_defaults(&mut self) -> Result<(), ConfigError<S::Error>> {
+        block_on(self.restore_defaults_async())
+    }
+
+    /**
+     * Reads an `EntryKind::U32` entry.
+     */
+    pub fn get_u32(&mut self, name: &str) -> Result<u32, ConfigError<S::Error>> {
+        block_on(self.get_u32_async(name))
+    }
+
+    /**
+     * Reads an `EntryKind::I32` entry.
+     */
+    pub fn get_i32(&mut self, name: &str) -> Result<i32, ConfigError<S::Error>> {
+        block_on(self.get_i32_async(name))
+    }
+
+    /**
+     * Reads an `EntryKind::Bool` entry.
+     */
+    pub fn get_bool(&mut self, name: &str) -> Result<bool, ConfigError<S::Error>> {
+        block_on(self.get_bool_async(name))
+    }
+
+    /**
+     * Reads an `EntryKind::Choice` entry, as the index of the choice.
+     */
+    pub fn get_choice(&mut self, name: &str) -> Result<usize, ConfigError<S::Error>> {
+        block_on(self.get_choice_async(name))
+    }
+
+    /**
+     * Reads an `EntryKind::Ipv4` entry.
+     */
+    pub fn get_ipv4(&mut self, name: &str) -> Result<Ipv4Addr, ConfigError<S::Error>> {
+        block_on(self.get_ipv4_async(name))
+    }
+
+    /**
+     * Reads an `EntryKind::F32` entry.
+     */
+    pub fn get_f32(&mut self, name: &str) -> Result<f32, ConfigError<S::Error>> {
+        block_on(self.get_f32_async(name))
+    }
+}
+
+impl<'a, S: AsyncNorFlash, C: BlockCipher> ConfigMenu<'a, S, C, Async> {
+    /**
+     * Creates the menu like `new`, with async storage, so the other tasks can run while
+     * the storage is mounted.
+     */
+    pub async fn new_async(
+        values: &'a [ConfigEntry<'a>],
+        key: impl Into<CipherKey>,
+        cipher: C,
+        storage: S,
+        region: StorageRegion,
+        #[cfg(feature = "wifi")] wifi_sender: Sender<
+            'static,
+            CriticalSectionRawMutex,
+            ClientConfiguration,
+            1,
+        >,
+    ) -> Result<Self, ConfigError<S::Error>> {
+        let key = key.into();
+        #[cfg(feature = "wifi")]
+        let menu = Self::create(values, key, cipher, storage, region, false, wifi_sender);
+        #[cfg(not(feature = "wifi"))]
+        let menu = Self::create(values, key, cipher, storage, region, false);
+        menu.await
+    }
+
+    /**
+     * Creates the menu like `new_or_format`, with async storage.
+     */
+    pub async fn new_or_format_async(
+        values: &'a [ConfigEntry<'a>],
+        key: impl Into<CipherKey>,
+        cipher: C,
+        storage: S,
+        region: StorageRegion,
+        #[cfg(feature = "wifi")] wifi_sender: Sender<
+            'static,
+            CriticalSectionRawMutex,
+            ClientConfiguration,
+            1,
+        >,
+    ) -> Result<Self, ConfigError<S::Error>> {
+        let key = key.into();
+        #[cfg(feature = "wifi")]
+        let menu = Self::create(values, key, cipher, storage, region, true, wifi_sender);
+        #[cfg(not(feature = "wifi"))]
+        let menu = Self::create(values, key, cipher, storage, region, true);
+        menu.await
+    }
+}
+
+impl<'a, S: ErrorType, C: BlockCipher, M: Access<S>> ConfigMenu<'a, S, C, M> {
+    /**
+     * Creates the menu, see `new`, and `new_or_format` for `format`.
+     */
+    async fn create(
+        values: &'a [ConfigEntry<'a>],
+        key: CipherKey,
+        mut cipher: C,
+        storage: S,
+        region: StorageRegion,
+        format: bool,
+        #[cfg(feature = "wifi")] wifi_sender: Sender<
+            'static,
+            CriticalSectionRawMutex,
+            ClientConfiguration,
+            1,
+        >,
+    ) -> Result<Self, ConfigError<S::Error>> {
+        check_entries(values)?;
+        if region.end() > M::capacity(&storage) as u64 {
+            error!(
+                "Config region {:#x}..{:#x} is outside the storage",
+                region.offset,
+                region.end()
+            );
+            return Err(ConfigError::InvalidRegion);
+        }
+        let erase_size = M::ERASE_SIZE as u32;
+        if !region.offset.is_multiple_of(erase_size) || !region.len.is_multiple_of(erase_size) {
+            error!(
+                "Config region {:#x}..{:#x} is not aligned to erase size {:#x}",
+                region.offset,
+                region.end(),
+                M::ERASE_SIZE
+            );
+            return Err(ConfigError::InvalidRegion);
+        }
+
+        let mut store = RecordStore::<S, M>::new(storage, region).map_err(|_| {
+            error!("Config region needs at least two sectors");
+            ConfigError::InvalidRegion
+        })?;
+        let (needed, largest) = record_sizes(values);
+        if largest > RecordStore::<S, M>::MAX_RECORD_SIZE {
+            error!(
+                "Largest config entry needs {} bytes, but a record can be at most {}",
+                largest,
+                RecordStore::<S, M>::MAX_RECORD_SIZE
+            );
+            return Err(ConfigError::TooLong);
+        }
+        if needed + largest > store.capacity(largest) {
+            error!(
+                "Config entries need {} bytes, but region only has room for {}",
+                needed + largest,
+                store.capacity(largest)
+            );
+            return Err(ConfigError::Full);
+        }
+        if let Err(RecordError::Corrupt) = store.mount().await {
+            error!("Config storage is corrupt, formatting it");
+            store.format().await?;
+        }
+        match check_header(&mut store, &mut cipher, &key, values).await {
+            Err(
+                err @ (ConfigError::DecryptFailed
+                | ConfigError::UnsupportedVersion
+                | ConfigError::IntegrityFailed),
+            ) if format => {
+                error!("Config storage can not be opened ({}), formatting it", err);
+                let header = Header::new(&key, &mut cipher, schema_hash(values));
+                format_with_header(&mut store, &header).await?;
+            }
+            result => result?,
+        }
+
+        #[cfg(feature = "wifi")]
+        let [wifi_ssid, wifi_pass, wifi_autostart] = WIFI_ENTRIES;
 
         let config_menu = Self {
             entries: values,
@@ -66,254 +555,2065 @@ impl<'a> ConfigMenu<'a> {
             #[cfg(feature = "wifi")]
             wifi_autostart,
             key,
-            aes,
-            storage: FlashStorage::new(),
+            key_deriver: None,
+            cipher,
+            store,
         };
-        config_menu
+        Ok(config_menu)
+    }
+
+    /**
+     * Sets what makes keys from the passphrases entered in the menu, which enables
+     * changing the key from the menu.
+     */
+    pub fn with_key_deriver(mut self, key_deriver: &'a mut (dyn KeyDeriver + Send)) -> Self {
+        self.key_deriver = Some(key_deriver);
+        self
+    }
+
+    /**
+     * True if the key can be changed from the menu.
+     */
+    pub fn can_rekey(&self) -> bool {
+        self.key_deriver.is_some()
+    }
+
+    /**
+     * Number of sectors used for storing the config.
+     */
+    pub fn sector_count(&self) -> u32 {
+        self.store.sector_count()
+    }
+
+    /**
+     * Async version of `reset`.
+     */
+    pub async fn reset_async(&mut self) -> Result<(), ConfigError<S::Error>> {
+        self.store.format().await?;
+        check_header(&mut self.store, &mut self.cipher, &self.key, self.entries).await
+    }
+
+    /**
+     * The entry at the index in `all_entries`, which is the number the menu lists it
+     * with.
+     */
+    pub fn get_entry_index(&self, index: usize) -> Result<&ConfigEntry<'a>, ConfigError<S::Error>> {
+        self.all_entries().nth(index).ok_or(ConfigError::NotFound)
+    }
+
+    pub fn get_entry(&self, name: &str) -> Result<&ConfigEntry<'a>, ConfigError<S::Error>> {
+        self.all_entries()
+            .find(|entry| entry.check_name(name))
+            .ok_or(ConfigError::NotFound)
+    }
+
+    /**
+     * All the entries, the ones given to `new` followed by the wifi entries when the wifi
+     * feature is enabled.
+     */
+    pub fn all_entries(
+        &self,
+    ) -> impl Iterator<Item = &'a ConfigEntry<'a>> + Clone + use<'a, S, C, M> {
+        with_wifi(self.entries)
+    }
+
+    /**
+     * Async version of `store_entry`.
+     */
+    pub async fn store_entry_async(
+        &mut self,
+        name: &str,
+        input: &str,
+    ) -> Result<(), ConfigError<S::Error>> {
+        self.store_entries_async(&[(name, input)]).await
+    }
+
+    /**
+     * Async version of `store_entries`.
+     */
+    pub async fn store_entries_async(
+        &mut self,
+        values: &[(&str, &str)],
+    ) -> Result<(), ConfigError<S::Error>> {
+        let values = values.iter().map(|(name, value)| (*name, value.as_bytes()));
+        self.store_all(values, false).await
+    }
+
+    /**
+     * Async version of `store_values`.
+     */
+    pub async fn store_values_async(
+        &mut self,
+        values: &[(&str, &[u8])],
+    ) -> Result<(), ConfigError<S::Error>> {
+        self.store_all(values.iter().copied(), true).await
+    }
+
+    async fn store_all<'v>(
+        &mut self,
+        values: impl Iterator<Item = (&'v str, &'v [u8])> + Clone,
+        allow_binary: bool,
+    ) -> Result<(), ConfigError<S::Error>> {
+        let all = self.all_entries();
+        let lookup = |name: &str| all.clone().find(|entry| entry.check_name(name)).copied();
+
+        for (name, value) in values.clone() {
+            let entry = lookup(name).ok_or(ConfigError::NotFound)?;
+            if entry.is_binary() && !allow_binary {
+                return Err(ConfigError::WrongType);
+            }
+            if value.len() > entry.max_len {
+                return Err(ConfigError::TooLong);
+            }
+            if entry.is_binary() {
+                entry.validate_bytes(value)
+            } else {
+                core::str::from_utf8(value)
+                    .map_err(|_| "not ASCII")
+                    .and_then(|value| entry.validate(value))
+            }
+            .map_err(ConfigError::Invalid)?;
+        }
+
+        let lens = values
+            .clone()
+            .map(|(name, _)| lookup(name).map_or(0, |entry| entry.size()));
+        let mut txn = self.store.begin_txn(lens).await?;
+        for (name, value) in values {
+            let entry = lookup(name).ok_or(ConfigError::NotFound)?;
+            entry
+                .store(
+                    &self.key,
+                    &mut self.cipher,
+                    &mut self.store,
+                    &mut txn,
+                    value,
+                )
+                .await?;
+        }
+        self.store.commit(txn).await?;
+        Ok(())
+    }
+
+    /**
+     * Async version of `store_bytes`.
+     */
+    pub async fn store_bytes_async(
+        &mut self,
+        name: &str,
+        value: &[u8],
+    ) -> Result<(), ConfigError<S::Error>> {
+        let entry = *self.get_entry(name)?;
+        if !entry.is_binary() {
+            return Err(ConfigError::WrongType);
+        }
+        if value.len() > entry.max_len {
+            return Err(ConfigError::TooLong);
+        }
+        entry.validate_bytes(value).map_err(ConfigError::Invalid)?;
+
+        let mut txn = self.store.begin_txn(iter::once(entry.size())).await?;
+        entry
+            .store(
+                &self.key,
+                &mut self.cipher,
+                &mut self.store,
+                &mut txn,
+                value,
+            )
+            .await?;
+        self.store.commit(txn).await?;
+        Ok(())
+    }
+
+    /**
+     * Async version of `begin_value`.
+     */
+    pub async fn begin_value_async(
+        &mut self,
+        name: &str,
+    ) -> Result<ValueWriter<'a>, ConfigError<S::Error>> {
+        let entry = *self.get_entry(name)?;
+        if !matches!(entry.kind, EntryKind::Text | EntryKind::Bytes)
+            || entry
+                .constraints
+                .iter()
+                .any(|constraint| constraint.needs_whole_value())
+        {
+            return Err(ConfigError::Invalid("can not be written in parts"));
+        }
+
+        let mut txn = self.store.begin_txn(iter::once(entry.size())).await?;
+        let writer = entry
+            .begin_write(&self.key, &mut self.cipher, &mut self.store, &mut txn)
+            .await?;
+        Ok(ValueWriter { writer, txn })
+    }
+
+    /**
+     * Async version of `write_value`.
+     */
+    pub async fn write_value_async(
+        &mut self,
+        value: &mut ValueWriter<'a>,
+        bytes: &[u8],
+    ) -> Result<(), ConfigError<S::Error>> {
+        if !self.store.is_open(&value.txn) {
+            return Err(ConfigError::Interrupted);
+        }
+        let entry = value.writer.entry;
+        if !entry.is_binary() {
+            if !bytes.is_ascii() || bytes.contains(&0) {
+                return Err(ConfigError::Invalid("not ASCII"));
+            }
+            let text =
+                core::str::from_utf8(bytes).map_err(|_| ConfigError::Invalid("not ASCII"))?;
+            entry
+                .constraints
+                .iter()
+                .try_for_each(|constraint| constraint.check_part(text))
+                .map_err(ConfigError::Invalid)?;
+        }
+        if value.writer.len + bytes.len() > entry.max_len {
+            return Err(ConfigError::TooLong);
+        }
+        value
+            .writer
+            .write(&mut self.cipher, &mut self.store, bytes)
+            .await?;
+        Ok(())
+    }
+
+    /**
+     * Async version of `finish_value`.
+     */
+    pub async fn finish_value_async(
+        &mut self,
+        value: ValueWriter<'a>,
+    ) -> Result<(), ConfigError<S::Error>> {
+        let ValueWriter { writer, txn } = value;
+        writer
+            .entry
+            .constraints
+            .iter()
+            .try_for_each(|constraint| constraint.check_len(writer.len))
+            .map_err(ConfigError::Invalid)?;
+        writer.finish(&mut self.cipher, &mut self.store).await?;
+        self.store.commit(txn).await?;
+        Ok(())
+    }
+
+    /**
+     * Async version of `read_bytes`.
+     */
+    pub async fn read_bytes_async(
+        &mut self,
+        name: &str,
+        output: &mut [u8],
+    ) -> Result<usize, ConfigError<S::Error>> {
+        let entry = *self.get_entry(name)?;
+        if !entry.is_binary() {
+            return Err(ConfigError::WrongType);
+        }
+        let result = entry
+            .read_bytes(&self.key, &mut self.cipher, &mut self.store, output)
+            .await;
+        lookup_or_default(&entry, result, |default| {
+            let default = default.as_bytes();
+            let output = output
+                .get_mut(..default.len())
+                .ok_or(ConfigError::OutputTooSmall)?;
+            output.copy_from_slice(default);
+            Ok(default.len())
+        })
+    }
+
+    /**
+     * Async version of `rekey`.
+     */
+    pub async fn rekey_async(
+        &mut self,
+        old_key: impl Into<CipherKey>,
+        new_key: impl Into<CipherKey>,
+    ) -> Result<(), ConfigError<S::Error>> {
+        let old_key = old_key.into();
+        let new_key = new_key.into();
+        // the values can not tell a wrong key when nothing is stored yet
+        if old_key != self.key {
+            error!("Can not change key, the old key is wrong");
+            return Err(ConfigError::DecryptFailed);
+        }
+        let all = self.all_entries();
+        for entry in all.clone() {
+            if let Err(err) = entry
+                .open(&old_key, &mut self.cipher, &mut self.store)
+                .await
+            {
+                error!("Can not change key, {}: {}", entry.name, err);
+                return Err(err);
+            }
+        }
+
+        let lens = all
+            .clone()
+            .map(|entry| entry.size())
+            .chain(iter::once(Header::SIZE));
+        let mut txn = self.store.begin_txn(lens).await?;
+        for entry in all {
+            entry
+                .rekey(
+                    &old_key,
+                    &new_key,
+                    &mut self.cipher,
+                    &mut self.store,
+                    &mut txn,
+                )
+                .await?;
+        }
+        let header = Header::new(&new_key, &mut self.cipher, schema_hash(self.entries)).to_bytes();
+        let mut writer = self
+            .store
+            .begin(&mut txn, HEADER_ID, Header::SIZE, 0)
+            .await?;
+        self.store.write(&mut writer, &header).await?;
+        self.store.finish(writer).await?;
+        self.store.commit(txn).await?;
+
+        self.key = new_key;
+        Ok(())
+    }
+
+    /**
+     * Async version of `read_entry`.
+     */
+    pub async fn read_entry_async<const MAX_SZ: usize>(
+        &mut self,
+        name: &str,
+        output: &mut heapless::String<MAX_SZ>,
+    ) -> Result<(), ConfigError<S::Error>> {
+        let entry = *self.get_entry(name)?;
+        if entry.is_binary() {
+            return Err(ConfigError::WrongType);
+        }
+        let result = entry
+            .read(&self.key, &mut self.cipher, &mut self.store, output)
+            .await;
+        lookup_or_default(&entry, result, |default| {
+            output.clear();
+            output
+                .push_str(default)
+                .map_err(|_| ConfigError::OutputTooSmall)
+        })
+    }
+
+    /**
+     * Async version of `read_entry_with`.
+     */
+    pub async fn read_entry_with_async(
+        &mut self,
+        name: &str,
+        mut f: impl FnMut(&[u8]),
+    ) -> Result<usize, ConfigError<S::Error>> {
+        let entry = *self.get_entry(name)?;
+        let result = entry
+            .read_chunks(&self.key, &mut self.cipher, &mut self.store, |chunk| {
+                f(chunk);
+                Ok(())
+            })
+            .await;
+        // nothing has been given to `f` when the value can not be opened
+        lookup_or_default(&entry, result, |default| {
+            f(default.as_bytes());
+            Ok(default.len())
+        })
+    }
+
+    /**
+     * Async version of `restore_default`.
+     */
+    pub async fn restore_default_async(&mut self, name: &str) -> Result<(), ConfigError<S::Error>> {
+        let entry = *self.get_entry(name)?;
+        let mut txn = self.store.begin_txn(iter::once(0)).await?;
+        self.store.remove(&mut txn, entry.id).await?;
+        Ok(self.store.commit(txn).await?)
+    }
+
+    /**
+     * Async version of `restore_defaults`.
+     */
+    pub async fn restore_defaults_async(&mut self) -> Result<(), ConfigError<S::Error>> {
+        let all = self.all_entries();
+        let mut txn = self.store.begin_txn(all.clone().map(|_| 0)).await?;
+        for entry in all {
+            self.store.remove(&mut txn, entry.id).await?;
+        }
+        Ok(self.store.commit(txn).await?)
+    }
+
+    /**
+     * Async version of `erase_count`.
+     */
+    pub async fn erase_count_async(&mut self, sector: u32) -> Result<u32, ConfigError<S::Error>> {
+        Ok(self.store.erase_count(sector).await?)
+    }
+
+    /**
+     * Async version of `rekey_passphrase`.
+     */
+    pub async fn rekey_passphrase_async(
+        &mut self,
+        old: &str,
+        new: &str,
+    ) -> Result<(), ConfigError<S::Error>> {
+        let Some(key_deriver) = self.key_deriver.as_mut() else {
+            error!("No key deriver set, can not change key from passphrase");
+            return Err(ConfigError::NoKeyDeriver);
+        };
+        let old_key = key_deriver.derive(old);
+        let new_key = key_deriver.derive(new);
+        self.rekey_async(old_key, new_key).await
+    }
+
+    /**
+     * Sends the stored wifi credentials to the wifi task if `wifi_autostart` is set.
+     * Does nothing when autostart or the credentials are not set, and fails when they
+     * can not be read.
+     */
+    #[cfg(feature = "wifi")]
+    pub async fn autostart_wifi(&mut self) -> Result<(), ConfigError<S::Error>> {
+        match self.get_bool_async("wifi_autostart").await {
+            Ok(true) => {}
+            Ok(false) | Err(ConfigError::NotSet) => return Ok(()),
+            Err(err) => return Err(err),
+        }
+        let mut ssid = heapless::String::<32>::new();
+        let mut pass = heapless::String::<64>::new();
+        let read = match self.read_entry_async("wifi_ssid", &mut ssid).await {
+            Ok(()) => self.read_entry_async("wifi_pass", &mut pass).await,
+            err => err,
+        };
+        match read {
+            Ok(()) => {}
+            Err(ConfigError::NotSet) => return Ok(()),
+            Err(err) => return Err(err),
+        }
+        let client_config = ClientConfiguration {
+            ssid,
+            password: pass,
+            ..Default::default()
+        };
+        self.wifi_sender.send(client_config).await;
+        Ok(())
+    }
+
+    /**
+     * Async version of `read_bytes_vec`.
+     */
+    pub async fn read_bytes_vec_async<const N: usize>(
+        &mut self,
+        name: &str,
+        output: &mut heapless::Vec<u8, N>,
+    ) -> Result<(), ConfigError<S::Error>> {
+        output.clear();
+        let _ = output.resize_default(N);
+        match self.read_bytes_async(name, output).await {
+            Ok(len) => {
+                output.truncate(len);
+                Ok(())
+            }
+            Err(err) => {
+                output.clear();
+                Err(err)
+            }
+        }
+    }
+
+    /**
+     * Async version of `load`.
+     */
+    pub async fn load_async<T: Config>(&mut self) -> Result<T, ConfigError<S::Error>> {
+        T::load_async(self).await
+    }
+
+    /**
+     * Async version of `save`.
+     */
+    pub async fn save_async<T: Config>(&mut self, config: &T) -> Result<(), ConfigError<S::Error>> {
+        config.save_async(self).await
+    }
+
+    /**
+     * Async version of `get_u32`.
+     */
+    pub async fn get_u32_async(&mut self, name: &str) -> Result<u32, ConfigError<S::Error>> {
+        let text = self
+            .read_kind(name, |kind| matches!(kind, EntryKind::U32 { .. }))
+            .await?;
+        text.parse()
+            .map_err(|_| ConfigError::Invalid("invalid value"))
+    }
+
+    /**
+     * Async version of `get_i32`.
+     */
+    pub async fn get_i32_async(&mut self, name: &str) -> Result<i32, ConfigError<S::Error>> {
+        let text = self
+            .read_kind(name, |kind| matches!(kind, EntryKind::I32 { .. }))
+            .await?;
+        text.parse()
+            .map_err(|_| ConfigError::Invalid("invalid value"))
+    }
+
+    /**
+     * Async version of `get_bool`.
+     */
+    pub async fn get_bool_async(&mut self, name: &str) -> Result<bool, ConfigError<S::Error>> {
+        let text = self
+            .read_kind(name, |kind| matches!(kind, EntryKind::Bool))
+            .await?;
+        parse_bool(&text).map_err(|_| ConfigError::Invalid("invalid value"))
+    }
+
+    /**
+     * Async version of `get_choice`.
+     */
+    pub async fn get_choice_async(&mut self, name: &str) -> Result<usize, ConfigError<S::Error>> {
+        let text = self
+            .read_kind(name, |kind| matches!(kind, EntryKind::Choice(_)))
+            .await?;
+        let entry = self.get_entry(name)?;
+        entry
+            .kind
+            .choice(&text)
+            .map_err(|_| ConfigError::Invalid("invalid value"))
+    }
+
+    /**
+     * Async version of `get_ipv4`.
+     */
+    pub async fn get_ipv4_async(&mut self, name: &str) -> Result<Ipv4Addr, ConfigError<S::Error>> {
+        let text = self
+            .read_kind(name, |kind| matches!(kind, EntryKind::Ipv4))
+            .await?;
+        parse_ipv4(&text).map_err(|_| ConfigError::Invalid("invalid value"))
+    }
+
+    /**
+     * Async version of `get_f32`.
+     */
+    pub async fn get_f32_async(&mut self, name: &str) -> Result<f32, ConfigError<S::Error>> {
+        let text = self
+            .read_kind(name, |kind| matches!(kind, EntryKind::F32 { .. }))
+            .await?;
+        text.parse()
+            .map_err(|_| ConfigError::Invalid("invalid value"))
+    }
+
+    /**
+     * Reads the text of an entry of the kind, and checks that it is still valid, since
+     * the kind may have changed since it was stored.
+     */
+    async fn read_kind(
+        &mut self,
+        name: &str,
+        is_kind: impl Fn(&EntryKind) -> bool,
+    ) -> Result<heapless::String<MAX_TEXT_LEN>, ConfigError<S::Error>> {
+        let entry = *self.get_entry(name)?;
+        if !is_kind(&entry.kind) {
+            return Err(ConfigError::WrongType);
+        }
+        let mut text = heapless::String::new();
+        self.read_entry_async(name, &mut text).await?;
+        entry
+            .kind
+            .check(&text)
+            .map_err(|_| ConfigError::Invalid("invalid value"))?;
+        Ok(text)
+    }
+}
+
+/**
+ * Reason an operation on the config failed. `E` is the error type of the storage.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ConfigError<E> {
+    /** There is no entry with the name, or at the index. */
+    NotFound,
+    /** The entry has never been written. */
+    NotSet,
+    /** The value is longer than the entry can hold. */
+    TooLong,
+    /** The value does not fit in the output. */
+    OutputTooSmall,
+    /** The stored value of a text entry is not ASCII text. */
+    InvalidUtf8,
+    /** The value is not valid for the entry, for the reason given. */
+    Invalid(&'static str),
+    /** The entry is of another kind than the value. */
+    WrongType,
+    /** The authentication tag does not match: written with another key, or tampered. */
+    DecryptFailed,
+    /** The stored data does not match its CRC, so it has been damaged in flash. */
+    IntegrityFailed,
+    /** Another value was stored while the value was written in parts. */
+    Interrupted,
+    /** The key can not be changed from passphrases without a key deriver. */
+    NoKeyDeriver,
+    /** There is no room for the value in the storage region. */
+    Full,
+    /** The table of entries has a mistake, see `check_layout`. */
+    InvalidLayout,
+    /** The storage region is outside the storage, not aligned, or too small. */
+    InvalidRegion,
+    /** The storage was written by a newer format version. */
+    UnsupportedVersion,
+    /** Reading or writing the storage failed. */
+    Storage(E),
+}
+
+impl<E: fmt::Debug> fmt::Display for ConfigError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            ConfigError::NotFound => "unknown entry",
+            ConfigError::NotSet => "not set",
+            ConfigError::TooLong => "too long",
+            ConfigError::OutputTooSmall => "does not fit in the output",
+            ConfigError::InvalidUtf8 => "not valid text",
+            ConfigError::Invalid(reason) => reason,
+            ConfigError::WrongType => "wrong type",
+            ConfigError::DecryptFailed => "wrong key",
+            ConfigError::IntegrityFailed => "corrupted",
+            ConfigError::Interrupted => "interrupted by another write",
+            ConfigError::NoKeyDeriver => "no key deriver",
+            ConfigError::Full => "storage is full",
+            ConfigError::InvalidLayout => "invalid entries",
+            ConfigError::InvalidRegion => "invalid storage region",
+            ConfigError::UnsupportedVersion => "written by a newer version",
+            ConfigError::Storage(err) => return write!(f, "storage failure: {:?}", err),
+        };
+        f.write_str(reason)
+    }
+}
+
+impl<E> From<RecordError<E>> for ConfigError<E> {
+    fn from(err: RecordError<E>) -> Self {
+        match err {
+            RecordError::Flash(err) => ConfigError::Storage(err),
+            RecordError::Full => ConfigError::Full,
+            RecordError::Corrupt => ConfigError::IntegrityFailed,
+            RecordError::TooLarge => ConfigError::TooLong,
+            RecordError::Interrupted => ConfigError::Interrupted,
+        }
+    }
+}
+
+/**
+ * Value being written in parts, see `ConfigMenu::begin_value`.
+ */
+pub struct ValueWriter<'a> {
+    writer: EntryWriter<'a>,
+    txn: Transaction,
+}
+
+impl<'a> ValueWriter<'a> {
+    /**
+     * Name of the entry the value is written to.
+     */
+    pub fn name(&self) -> &'a str {
+        self.writer.entry.name
+    }
+
+    /**
+     * Number of bytes written so far.
+     */
+    pub fn written(&self) -> usize {
+        self.writer.len
+    }
+}
+
+/**
+ * Gives the result of reading the value of the entry, or if the entry has a default and
+ * the value is not stored or can not be read, the result of `use_default` with the
+ * default.
+ */
+fn lookup_or_default<T, E: fmt::Debug>(
+    entry: &ConfigEntry,
+    result: Result<T, ConfigError<E>>,
+    use_default: impl FnOnce(&str) -> Result<T, ConfigError<E>>,
+) -> Result<T, ConfigError<E>> {
+    let Some(default) = entry.default else {
+        return result;
+    };
+    match result {
+        Err(
+            err @ (ConfigError::NotSet
+            | ConfigError::InvalidUtf8
+            | ConfigError::IntegrityFailed
+            | ConfigError::DecryptFailed),
+        ) => {
+            if !matches!(err, ConfigError::NotSet) {
+                error!("Config entry {} is {}, using default", entry.name, err);
+            }
+            use_default(default)
+        }
+        result => result,
+    }
+}
+
+/**
+ * Reads the header, or writes it if the storage is empty, and checks that the storage
+ * was written in this format, with the same key, and for the same entries. If the entries
+ * have changed, the values are migrated to the new entries. Storage in an unknown or
+ * older format, like by earlier versions of the crate, is formatted. Fails on a format
+ * version from a newer firmware, or another key, so the values are kept for the right
+ * firmware or key.
+ */
+async fn check_header<S: ErrorType, M: Access<S>>(
+    store: &mut RecordStore<S, M>,
+    cipher: &mut impl BlockCipher,
+    key: &CipherKey,
+    values: &[ConfigEntry<'_>],
+) -> Result<(), ConfigError<S::Error>> {
+    let expected = Header::new(key, cipher, schema_hash(values));
+    let Some(record) = store.find(HEADER_ID).await? else {
+        return Ok(store.append(HEADER_ID, 0, &expected.to_bytes()).await?);
+    };
+
+    if !store.verify(&record).await? {
+        error!("Config storage header is corrupt");
+        return Err(ConfigError::IntegrityFailed);
+    }
+    let mut bytes = [0u8; Header::SIZE];
+    let header = if record.len as usize == Header::SIZE {
+        store.read(&record, 0, &mut bytes).await?;
+        Header::from_bytes(&bytes)
+    } else {
+        None
+    };
+    let Some(header) = header else {
+        error!("Config storage is in an unknown format, formatting it");
+        return format_with_header(store, &expected).await;
+    };
+
+    if header.version < Header::VERSION {
+        error!(
+            "Config storage has old format version {}, formatting it",
+            header.version
+        );
+        return format_with_header(store, &expected).await;
+    }
+    if header.version > Header::VERSION {
+        error!(
+            "Config storage has format version {}, but only {} is supported",
+            header.version,
+            Header::VERSION
+        );
+        return Err(ConfigError::UnsupportedVersion);
+    }
+    if header.key_bits != expected.key_bits {
+        error!(
+            "Config storage was written with a {} bit key, but the key is {} bits",
+            header.key_bits, expected.key_bits
+        );
+        return Err(ConfigError::DecryptFailed);
+    }
+    if header.key_check != expected.key_check {
+        error!("Config storage was written with another key");
+        return Err(ConfigError::DecryptFailed);
     }
+    if header.schema != expected.schema {
+        info!(
+            "Config entries have changed (schema {:#010x}, was {:#010x}), migrating values",
+            expected.schema, header.schema
+        );
+        migrate(store, cipher, key, values).await?;
+        return Ok(store.append(HEADER_ID, 0, &expected.to_bytes()).await?);
+    }
+    Ok(())
+}
 
-    #[cfg(feature = "wifi")]
-    pub async fn autostart_wifi(&mut self) {
-        let mut autostart = heapless::String::<32>::new();
-        if let Ok(_) =
-            self.wifi_autostart
-                .read(&self.key, &mut self.aes, &mut self.storage, &mut autostart)
-        {
-            if autostart == "yes" {
-                let mut ok = true;
-
-                let mut ssid = heapless::String::<32>::new();
-                if let Err(_) =
-                    self.wifi_ssid
-                        .read(&self.key, &mut self.aes, &mut self.storage, &mut ssid)
-                {
-                    ok = false;
-                }
+async fn format_with_header<S: ErrorType, M: Access<S>>(
+    store: &mut RecordStore<S, M>,
+    header: &Header,
+) -> Result<(), ConfigError<S::Error>> {
+    store.format().await?;
+    Ok(store.append(HEADER_ID, 0, &header.to_bytes()).await?)
+}
 
-                let mut pass = heapless::String::<64>::new();
-                if let Err(_) =
-                    self.wifi_pass
-                        .read(&self.key, &mut self.aes, &mut self.storage, &mut pass)
-                {
-                    ok = false;
-                }
+/** Number of removed entries `migrate` collects in one scan of the store. */
+const REMOVED_BATCH: usize = 16;
 
-                if ok {
-                    let client_config = ClientConfiguration {
-                        ssid,
-                        password: pass,
-                        ..Default::default()
-                    };
-                    let _ = self.wifi_sender.send(client_config).await;
+/**
+ * Moves the stored values to the entries of the firmware, when they have changed since
+ * the values were written. Values are found by the id of the entry, which is made from
+ * the name, so values of entries that are still there are kept. Values of entries that
+ * have been removed are removed, and entries that have been added are not set. Values
+ * longer than the `max_len` of their entry are removed, and values of entries that have
+ * changed size are written again in the new size. The header is written after this, so
+ * if the power is lost the migration is done again at the next boot.
+ */
+async fn migrate<S: ErrorType, M: Access<S>>(
+    store: &mut RecordStore<S, M>,
+    cipher: &mut impl BlockCipher,
+    key: &CipherKey,
+    values: &[ConfigEntry<'_>],
+) -> Result<(), ConfigError<S::Error>> {
+    let lookup = |id: u32| with_wifi(values).find(|entry| entry.id == id);
+
+    // the keys of removed entries are collected in one scan of the store, in increasing
+    // order, and only when there are more than fit in `removed` the store is scanned
+    // again for the keys after them
+    let mut last = None;
+    loop {
+        let mut removed = heapless::Vec::<u32, REMOVED_BATCH>::new();
+        let mut more = false;
+        store
+            .for_each_key(|id| {
+                if id >= HEADER_ID || last.is_some_and(|last| id <= last) || lookup(id).is_some() {
+                    return;
+                }
+                let Err(pos) = removed.binary_search(&id) else {
+                    return;
+                };
+                if removed.is_full() {
+                    more = true;
+                    if pos == removed.len() {
+                        return;
+                    }
+                    removed.pop();
                 }
+                let _ = removed.insert(pos, id);
+            })
+            .await?;
+
+        for &id in removed.iter() {
+            if store.find(id).await?.is_some() {
+                info!("Removing value of removed config entry {:#010x}", id);
+                let mut txn = store.begin_txn(iter::once(0)).await?;
+                store.remove(&mut txn, id).await?;
+                store.commit(txn).await?;
             }
         }
+        if !more {
+            break;
+        }
+        last = removed.last().copied();
     }
 
-    pub fn get_entry_index(&self, index: usize) -> Result<&ConfigEntry, ()> {
-        if index >= self.entries.len() {
-            return Err(());
+    for entry in with_wifi(values) {
+        let Some(record) = store.find(entry.id).await? else {
+            continue;
+        };
+        let resized = record.len as usize != entry.size();
+        match entry.fits(key, cipher, store).await {
+            Ok(true) if resized => {
+                info!("Resizing value of config entry {}", entry.name);
+                let mut txn = store.begin_txn(iter::once(entry.size())).await?;
+                entry.rekey(key, key, cipher, store, &mut txn).await?;
+                store.commit(txn).await?;
+            }
+            Ok(true) => {}
+            Ok(false) => {
+                error!(
+                    "Value of config entry {} does not fit its new size, removing it",
+                    entry.name
+                );
+                let mut txn = store.begin_txn(iter::once(0)).await?;
+                store.remove(&mut txn, entry.id).await?;
+                store.commit(txn).await?;
+            }
+            // left as it is, so reading it tells why it can not be read
+            Err(_) => {}
         }
-        Ok(&self.entries[index])
     }
+    Ok(())
+}
 
-    pub fn get_entry(&self, name: &str) -> Result<&ConfigEntry, ()> {
-        for entry in self.entries.iter() {
-            if entry.check_name(name) {
-                return Ok(&entry);
-            }
+/**
+ * Checks the layout of the entries with `check_layout`, and that the defaults are
+ * valid values of the entries.
+ */
+fn check_entries<E>(values: &[ConfigEntry]) -> Result<(), ConfigError<E>> {
+    if let Err(err) = check_layout(values) {
+        error!("Config entries are not valid: {}", err);
+        return Err(ConfigError::InvalidLayout);
+    }
+    for entry in values.iter() {
+        let Some(default) = entry.default else {
+            continue;
+        };
+        let valid = if entry.is_binary() {
+            entry.validate_bytes(default.as_bytes())
+        } else {
+            entry.validate(default)
+        };
+        if let Err(reason) = valid {
+            error!(
+                "Default of config entry {} is not valid: {}",
+                entry.name, reason
+            );
+            return Err(ConfigError::Invalid(reason));
         }
+    }
+    Ok(())
+}
 
-        #[cfg(feature = "wifi")]
-        {
-            if name == "wifi_ssid" {
-                return Ok(&self.wifi_ssid);
-            }
-            if name == "wifi_pass" {
-                return Ok(&self.wifi_pass);
-            }
-            if name == "wifi_autostart" {
-                return Ok(&self.wifi_autostart);
-            }
+/**
+ * Mistake in the table of entries, found by `check_layout`.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LayoutError<'a> {
+    /** An entry has an empty name. */
+    EmptyName,
+    /** Two entries have the same name, so the second can not be reached. */
+    DuplicateName(&'a str),
+    /** The entry has the name of one of the wifi entries. */
+    ReservedName(&'a str),
+    /** The hashes of the names of the two entries are the same, so they share an id. */
+    SameId(&'a str, &'a str),
+    /** The entry has a `max_len` of 0, so it can not hold a value. */
+    ZeroSize(&'a str),
+    /** The entry is larger than a record can be. */
+    TooLarge(&'a str),
+    /** The binary entry has a constraint that only applies to text. */
+    TextConstraint(&'a str),
+}
+
+impl LayoutError<'_> {
+    /**
+     * Describes the mistake, without the names of the entries, so it can be used in
+     * const panics.
+     */
+    pub const fn message(&self) -> &'static str {
+        match self {
+            LayoutError::EmptyName => "config entry has an empty name",
+            LayoutError::DuplicateName(_) => "config entry name is used twice",
+            LayoutError::ReservedName(_) => "config entry name is used by a wifi entry",
+            LayoutError::SameId(_, _) => "config entry names hash to the same id",
+            LayoutError::ZeroSize(_) => "config entry has a max_len of 0",
+            LayoutError::TooLarge(_) => "config entry is too large",
+            LayoutError::TextConstraint(_) => "binary config entry has a text constraint",
         }
-        Err(())
     }
+}
 
-    pub fn store_entry(&mut self, name: &str, input: &str) -> Result<(), ()> {
-        for entry in self.entries.iter() {
-            if entry.check_name(name) {
-                return entry.store(&self.key, &mut self.aes, &mut self.storage, input);
-            }
+impl fmt::Display for LayoutError<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.message())?;
+        match self {
+            LayoutError::EmptyName => Ok(()),
+            LayoutError::DuplicateName(name)
+            | LayoutError::ReservedName(name)
+            | LayoutError::ZeroSize(name)
+            | LayoutError::TooLarge(name)
+            | LayoutError::TextConstraint(name) => write!(f, ": {}", name),
+            LayoutError::SameId(name, other) => write!(f, ": {} and {}", name, other),
         }
-        #[cfg(feature = "wifi")]
-        if name == "wifi_ssid" {
-            return self
-                .wifi_ssid
-                .store(&self.key, &mut self.aes, &mut self.storage, input);
+    }
+}
+
+/** Names of the entries added by the wifi feature. */
+#[cfg(feature = "wifi")]
+const WIFI_NAMES: [&str; 3] = ["wifi_ssid", "wifi_pass", "wifi_autostart"];
+#[cfg(not(feature = "wifi"))]
+const WIFI_NAMES: [&str; 0] = [];
+
+/**
+ * Checks the table of entries for mistakes: empty or duplicate names, names used by the
+ * wifi entries, names with the same id, entries that can not hold a value or are too
+ * large, and binary entries with constraints other than `MinLen` and `MaxLen`.
+ * `ConfigMenu::new` fails on these, and since this is a const fn, a table known at
+ * compile time can be checked when building, like `declare_config!` does:
+ *
+ * ```ignore
+ * const _: () = if let Err(err) = check_layout(ENTRIES) {
+ *     panic!("{}", err.message())
+ * };
+ * ```
+ */
+pub const fn check_layout<'a>(values: &[ConfigEntry<'a>]) -> Result<(), LayoutError<'a>> {
+    check_layout_with(values, &WIFI_NAMES)
+}
+
+/** `check_layout` with the names of the entries that the table may not use. */
+const fn check_layout_with<'a>(
+    values: &[ConfigEntry<'a>],
+    reserved: &[&'a str],
+) -> Result<(), LayoutError<'a>> {
+    let mut i = 0;
+    while i < values.len() {
+        let entry = &values[i];
+        if entry.name.is_empty() {
+            return Err(LayoutError::EmptyName);
         }
-        #[cfg(feature = "wifi")]
-        if name == "wifi_pass" {
-            return self
-                .wifi_pass
-                .store(&self.key, &mut self.aes, &mut self.storage, input);
+        if entry.max_len == 0 {
+            return Err(LayoutError::ZeroSize(entry.name));
         }
-        #[cfg(feature = "wifi")]
-        if name == "wifi_autostart" {
-            return self
-                .wifi_autostart
-                .store(&self.key, &mut self.aes, &mut self.storage, input);
+        if entry.size() > u16::MAX as usize {
+            return Err(LayoutError::TooLarge(entry.name));
         }
-
-        Err(())
-    }
-
-    pub fn read_entry<const MAX_SZ: usize>(
-        &mut self,
-        name: &str,
-        output: &mut heapless::String<MAX_SZ>,
-    ) -> Result<(), ()> {
-        for value in self.entries.iter() {
-            if value.check_name(name) {
-                return value.read(&self.key, &mut self.aes, &mut self.storage, output);
+        let mut j = 0;
+        while j < entry.constraints.len() {
+            let length = matches!(
+                entry.constraints[j],
+                Constraint::MinLen(_) | Constraint::MaxLen(_)
+            );
+            if entry.is_binary() && !length {
+                return Err(LayoutError::TextConstraint(entry.name));
             }
+            j += 1;
         }
-
-        #[cfg(feature = "wifi")]
-        {
-            if name == "wifi_ssid" {
-                return self
-                    .wifi_ssid
-                    .read(&self.key, &mut self.aes, &mut self.storage, output);
+        let mut j = 0;
+        while j < reserved.len() {
+            if same_name(entry.name, reserved[j]) {
+                return Err(LayoutError::ReservedName(entry.name));
             }
-            if name == "wifi_pass" {
-                return self
-                    .wifi_pass
-                    .read(&self.key, &mut self.aes, &mut self.storage, output);
+            if entry.id == entry_id(reserved[j]) {
+                return Err(LayoutError::SameId(entry.name, reserved[j]));
             }
-            if name == "wifi_autostart" {
-                return self.wifi_autostart.read(
-                    &self.key,
-                    &mut self.aes,
-                    &mut self.storage,
-                    output,
-                );
+            j += 1;
+        }
+        let mut j = i + 1;
+        while j < values.len() {
+            let other = &values[j];
+            if same_name(entry.name, other.name) {
+                return Err(LayoutError::DuplicateName(entry.name));
             }
+            if entry.id == other.id {
+                return Err(LayoutError::SameId(entry.name, other.name));
+            }
+            j += 1;
+        }
+        i += 1;
+    }
+    Ok(())
+}
+
+const fn same_name(a: &str, b: &str) -> bool {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    if a.len() != b.len() {
+        return false;
+    }
+    let mut i = 0;
+    while i < a.len() {
+        if a[i] != b[i] {
+            return false;
         }
-        Err(())
+        i += 1;
     }
+    true
 }
 
-#[derive(Debug)]
+/**
+ * FNV-1a hash of the names and sizes of the entries, in order, including the wifi
+ * entries when the wifi feature is enabled.
+ */
+fn schema_hash(values: &[ConfigEntry]) -> u32 {
+    let mut hash = FNV_OFFSET;
+    let mut add = |value: &ConfigEntry| {
+        hash = fnv1a(hash, value.name.as_bytes());
+        hash = fnv1a(hash, &[0]);
+        hash = fnv1a(hash, &(value.n_blocks as u32).to_le_bytes());
+        hash = fnv1a(hash, &(value.max_len as u32).to_le_bytes());
+    };
+    with_wifi(values).for_each(&mut add);
+    hash
+}
+
+const FNV_OFFSET: u32 = 0x811c_9dc5;
+
+const fn fnv1a(mut hash: u32, bytes: &[u8]) -> u32 {
+    let mut i = 0;
+    while i < bytes.len() {
+        hash ^= bytes[i] as u32;
+        hash = hash.wrapping_mul(0x0100_0193);
+        i += 1;
+    }
+    hash
+}
+
+/**
+ * Id of the entry with the name, used as the key of its records. The ids from
+ * `HEADER_ID` and up are kept for the records of the menu itself.
+ */
+pub const fn entry_id(name: &str) -> u32 {
+    fnv1a(FNV_OFFSET, name.as_bytes()) % HEADER_ID
+}
+
+/**
+ * Sum of the record sizes of the entries and the header, including the wifi entries
+ * when the wifi feature is enabled, and the size of the largest record.
+ */
+fn record_sizes(values: &[ConfigEntry]) -> (u32, u32) {
+    let header = record_size(Header::SIZE);
+    let mut sizes = (header, header);
+    let mut add = |value: &ConfigEntry| {
+        let size = record_size(value.size());
+        sizes = (sizes.0 + size, sizes.1.max(size));
+    };
+    with_wifi(values).for_each(&mut add);
+    sizes
+}
+
+/**
+ * Length of the smallest region with 4 KiB sectors, like the esp32 flash, that has
+ * room for the entries. Useful for checking that a partition is large enough.
+ */
+pub fn required_len(values: &[ConfigEntry]) -> u32 {
+    const SECTOR_SIZE: u32 = 4096;
+    let (needed, largest) = record_sizes(values);
+    let per_sector = max_record_size(SECTOR_SIZE).saturating_sub(largest).max(1);
+    (1 + (needed + largest).div_ceil(per_sector)).max(2) * SECTOR_SIZE
+}
+
+/** The entries added by the wifi feature. */
+#[cfg(feature = "wifi")]
+static WIFI_ENTRIES: [ConfigEntry<'static>; 3] = [
+    ConfigEntry::new("wifi_ssid", 32, "Wifi SSID", false),
+    ConfigEntry::new("wifi_pass", 64, "Wifi Password", true),
+    ConfigEntry::new(
+        "wifi_autostart",
+        32,
+        "Should wifi be connected automatically at boot?",
+        false,
+    )
+    .with_kind(EntryKind::Bool)
+    .with_default("no"),
+];
+
+/**
+ * The entries followed by the wifi entries, when the wifi feature is enabled.
+ */
+fn with_wifi<'a>(
+    values: &'a [ConfigEntry<'a>],
+) -> impl Iterator<Item = &'a ConfigEntry<'a>> + Clone {
+    let entries = values.iter();
+    #[cfg(feature = "wifi")]
+    let entries = entries.chain(WIFI_ENTRIES.iter());
+    entries
+}
+
+#[derive(Debug, Clone, Copy)]
 pub struct ConfigEntry<'a> {
     pub name: &'a str,
     pub n_blocks: usize, // number of blocks of 16 bytes
-    pub offset: u32,
+    pub max_len: usize,  // longest value, in bytes
+    pub id: u32,         // key of the record in storage, made from the name
     pub question: &'a str,
     pub secret: bool,
+    pub kind: EntryKind<'a>,
+    pub default: Option<&'a str>,
+    pub constraints: &'a [Constraint<'a>],
 }
 
 impl<'a> ConfigEntry<'a> {
-    pub fn new(name: &'a str, max_len: usize, question: &'a str, secret: bool) -> Self {
+    pub const fn new(name: &'a str, max_len: usize, question: &'a str, secret: bool) -> Self {
         Self {
             name,
             n_blocks: max_len.div_ceil(16),
-            offset: 0,
+            max_len,
+            id: entry_id(name),
             question,
             secret,
+            kind: EntryKind::Text,
+            default: None,
+            constraints: &[],
         }
     }
 
+    /**
+     * Entry holding a binary value of up to `max_len` bytes, like a key or a certificate
+     * in DER format. The length of the value is stored with it, so the entry takes
+     * `max_len + 2` bytes, rounded up to whole blocks.
+     */
+    pub const fn bytes(name: &'a str, max_len: usize, question: &'a str, secret: bool) -> Self {
+        let mut entry = Self::new(name, max_len + LEN_SIZE, question, secret);
+        entry.max_len = max_len;
+        entry.kind = EntryKind::Bytes;
+        entry
+    }
+
+    /**
+     * Sets constraints the values have to meet, on top of the kind of the entry.
+     */
+    pub const fn with_constraints(mut self, constraints: &'a [Constraint<'a>]) -> Self {
+        self.constraints = constraints;
+        self
+    }
+
+    /**
+     * Sets the value read when the entry has never been written, or can not be read.
+     */
+    pub const fn with_default(mut self, default: &'a str) -> Self {
+        self.default = Some(default);
+        self
+    }
+
+    /**
+     * Sets the kind of value the entry holds, which is checked when storing it.
+     */
+    pub const fn with_kind(mut self, kind: EntryKind<'a>) -> Self {
+        self.kind = kind;
+        self
+    }
+
+    #[cfg(feature = "esp")]
     pub fn print(&self, cnt: i32, output: &str) {
+        self.print_header(cnt, output.len());
         if self.secret {
-            println!(
-                "{}: Entry: {} size: -/{}: ********",
-                cnt,
-                self.name,
-                16 * self.n_blocks
-            );
+            println!("********");
+        } else {
+            self.print_part(output.as_bytes());
+            println!("");
+        }
+    }
+
+    /**
+     * Prints the start of the line listing the entry, before the value, which is then
+     * printed with `print_part`, so large values can be printed in parts.
+     */
+    #[cfg(feature = "esp")]
+    pub fn print_header(&self, cnt: i32, len: usize) {
+        if self.secret {
+            print!("{}: Entry: {} size: -/{}: ", cnt, self.name, self.max_len);
+        } else if !matches!(self.kind, EntryKind::Text | EntryKind::Bytes) {
+            print!("{}: Entry: {} ({}): ", cnt, self.name, self.kind);
         } else {
-            println!(
-                "{}: Entry: {} size: {}/{}: {}",
-                cnt,
-                self.name,
-                output.len(),
-                16 * self.n_blocks,
-                output,
+            print!(
+                "{}: Entry: {} size: {}/{}: ",
+                cnt, self.name, len, self.max_len
             );
         }
     }
 
+    /**
+     * Prints a part of the value, as hex for binary values.
+     */
+    #[cfg(feature = "esp")]
+    pub fn print_part(&self, part: &[u8]) {
+        if self.is_binary() {
+            for byte in part {
+                print!("{:02x}", byte);
+            }
+        } else if let Ok(text) = core::str::from_utf8(part) {
+            print!("{}", text);
+        }
+    }
+
+    /**
+     * Checks that the binary value fits in the entry, and tells why if it does not.
+     */
+    pub fn validate_bytes(&self, value: &[u8]) -> Result<(), &'static str> {
+        if !self.is_binary() {
+            return Err("not a binary entry");
+        }
+        if value.len() > self.max_len {
+            return Err("too long");
+        }
+        self.constraints
+            .iter()
+            .try_for_each(|constraint| constraint.check_len(value.len()))
+    }
+
+    /**
+     * Checks that the value fits in the entry, is valid for its kind, and meets its
+     * constraints, and tells why if it does not.
+     */
+    pub fn validate(&self, value: &str) -> Result<(), &'static str> {
+        if value.len() > self.max_len {
+            return Err("too long");
+        }
+        // text is stored ending with a zero byte, so it can not hold one
+        if !value.is_ascii() || value.contains('\0') {
+            return Err("not ASCII");
+        }
+        self.kind.check(value)?;
+        self.constraints
+            .iter()
+            .try_for_each(|constraint| constraint.check(value))
+    }
+
+    /**
+     * True for entries holding binary values, made with `ConfigEntry::bytes`.
+     */
+    pub const fn is_binary(&self) -> bool {
+        matches!(self.kind, EntryKind::Bytes)
+    }
+
+    /**
+     * Number of bytes the entry uses in storage, including the nonce and the
+     * authentication tag.
+     */
+    pub const fn size(&self) -> usize {
+        NONCE_SIZE + 16 * self.n_blocks + TAG_SIZE
+    }
+
     fn check_name(&self, name: &str) -> bool {
         name == self.name
     }
 
-    pub fn store(
+    /**
+     * Encrypts the value with AES-GCM, and appends it to the store as a new record in
+     * the transaction. The record holds the nonce, the value padded with zeros to the
+     * size of the entry, and the authentication tag. Binary values have their length in
+     * the last two bytes. The name of the entry is used as associated data, so a record
+     * can not be moved to another entry.
+     */
+    pub async fn store<S: ErrorType, M: Access<S>>(
         &self,
-        key: &[u8; 16],
-        aes: &mut Aes,
-        storage: &mut FlashStorage,
-        value: &str,
-    ) -> Result<(), ()> {
-        if value.len() > 16 * self.n_blocks {
-            return Err(());
-        }
-
-        let mut cur_offset = self.offset;
-        for i in 0..self.n_blocks {
-            let start = 16 * i;
-            let mut end = 16 * (i + 1);
-            if end > value.len() {
-                end = value.len();
-            }
+        key: &CipherKey,
+        cipher: &mut impl BlockCipher,
+        store: &mut RecordStore<S, M>,
+        txn: &mut Transaction,
+        value: &[u8],
+    ) -> Result<(), ConfigError<S::Error>> {
+        if value.len() > self.max_len {
+            return Err(ConfigError::TooLong);
+        }
+        let mut writer = self.begin_write(key, cipher, store, txn).await?;
+        writer.write(cipher, store, value).await?;
+        Ok(writer.finish(cipher, store).await?)
+    }
 
-            let sub_str = if start >= value.len() {
-                ""
-            } else {
-                &value[start..end]
-            };
+    /**
+     * Starts a new record for the entry in the transaction, which the value is written
+     * to in parts.
+     */
+    async fn begin_write<S: ErrorType, M: Access<S>>(
+        &self,
+        key: &CipherKey,
+        cipher: &mut impl BlockCipher,
+        store: &mut RecordStore<S, M>,
+        txn: &mut Transaction,
+    ) -> Result<EntryWriter<'a>, RecordError<S::Error>> {
+        let mut record = store.begin(txn, self.id, self.size(), 0).await?;
+        let nonce = record.stamp();
+        store.write(&mut record, &nonce).await?;
+        Ok(EntryWriter {
+            entry: *self,
+            record,
+            gcm: Gcm::new(cipher, key, &nonce, self.name.as_bytes()),
+            block: [0_u8; 16],
+            fill: 0,
+            len: 0,
+            blocks: 0,
+        })
+    }
+
+    /**
+     * Reads and decrypts the last record stored for a binary entry into the output, and
+     * gives the length of the value.
+     */
+    pub async fn read_bytes<S: ErrorType, M: Access<S>>(
+        &self,
+        key: &CipherKey,
+        cipher: &mut impl BlockCipher,
+        store: &mut RecordStore<S, M>,
+        output: &mut [u8],
+    ) -> Result<usize, ConfigError<S::Error>> {
+        let mut pos = 0;
+        self.read_chunks(key, cipher, store, |chunk| {
+            let end = pos + chunk.len();
+            output
+                .get_mut(pos..end)
+                .ok_or(ConfigError::OutputTooSmall)?
+                .copy_from_slice(chunk);
+            pos = end;
+            Ok(())
+        })
+        .await
+    }
 
-            let mut block = [0_u8; 16];
-            block[..sub_str.len()].copy_from_slice(sub_str.as_bytes());
-            let k: Key = (*key).into();
-            aes.process(&mut block, Mode::Encryption128, k);
+    /**
+     * Reads and decrypts the last record stored for the entry, and calls `f` with each
+     * part of the value, so the value does not have to fit in memory. The whole record is
+     * checked before `f` is called. Gives the length of the value.
+     */
+    pub async fn read_chunks<S: ErrorType, M: Access<S>>(
+        &self,
+        key: &CipherKey,
+        cipher: &mut impl BlockCipher,
+        store: &mut RecordStore<S, M>,
+        mut f: impl FnMut(&[u8]) -> Result<(), ConfigError<S::Error>>,
+    ) -> Result<usize, ConfigError<S::Error>> {
+        let opened = self
+            .open(key, cipher, store)
+            .await?
+            .ok_or(ConfigError::NotSet)?;
+
+        let mut gcm = Gcm::new(cipher, key, &opened.nonce, self.name.as_bytes());
+        let mut block = [0_u8; 16];
+        let mut left = opened.len;
+        for i in 0..n_blocks(&opened.record) {
+            if left == 0 {
+                break;
+            }
+            store
+                .read(&opened.record, block_offset(i), &mut block)
+                .await?;
+            gcm.decrypt(cipher, &mut block);
 
-            let _ = storage.write(0x9000 + cur_offset, &block);
-            cur_offset += 16;
+            let chunk = &block[..left.min(16)];
+            f(chunk)?;
+            left -= chunk.len();
         }
-        Ok(())
+        Ok(opened.len)
     }
 
-    pub fn read<const MAX_SZ: usize>(
+    /**
+     * Reads and decrypts the last record stored for the entry. The CRC of the record
+     * is checked first, and then the authentication tag, before anything is decrypted.
+     */
+    pub async fn read<S: ErrorType, M: Access<S>, const MAX_SZ: usize>(
         &self,
-        key: &[u8; 16],
-        aes: &mut Aes,
-        storage: &mut FlashStorage,
+        key: &CipherKey,
+        cipher: &mut impl BlockCipher,
+        store: &mut RecordStore<S, M>,
         output: &mut heapless::String<MAX_SZ>,
-    ) -> Result<(), ()> {
+    ) -> Result<(), ConfigError<S::Error>> {
         output.clear();
-        let mut cur_offset = self.offset;
-        for _ in 0..self.n_blocks {
-            let mut block = [0_u8; 16];
-            let _ = storage.read(0x9000 + cur_offset, &mut block);
-            cur_offset += 16;
+        self.read_chunks(key, cipher, store, |chunk| {
+            let text = core::str::from_utf8(chunk).map_err(|_| ConfigError::InvalidUtf8)?;
+            output
+                .push_str(text)
+                .map_err(|_| ConfigError::OutputTooSmall)
+        })
+        .await?;
+        Ok(())
+    }
+
+    /**
+     * Decrypts the last record stored for the entry with the old key, and stores the
+     * value again encrypted with the new key, as a new record in the transaction. The
+     * value is re-encrypted a block at a time, so it is never held in memory as a whole.
+     * This also moves the value to the size of the entry, if it has changed. Does
+     * nothing if the entry has never been written.
+     */
+    pub async fn rekey<S: ErrorType, M: Access<S>>(
+        &self,
+        old_key: &CipherKey,
+        new_key: &CipherKey,
+        cipher: &mut impl BlockCipher,
+        store: &mut RecordStore<S, M>,
+        txn: &mut Transaction,
+    ) -> Result<(), ConfigError<S::Error>> {
+        let Some(opened) = self.open(old_key, cipher, store).await? else {
+            return Ok(());
+        };
+        if opened.len > self.max_len {
+            return Err(ConfigError::OutputTooSmall);
+        }
+
+        let mut writer = self.begin_write(new_key, cipher, store, txn).await?;
+        let mut gcm = Gcm::new(cipher, old_key, &opened.nonce, self.name.as_bytes());
+        let mut block = [0_u8; 16];
+        let mut left = opened.len;
+        for i in 0..n_blocks(&opened.record) {
+            if left == 0 {
+                break;
+            }
+            store
+                .read(&opened.record, block_offset(i), &mut block)
+                .await?;
+            gcm.decrypt(cipher, &mut block);
+
+            let chunk = &block[..left.min(16)];
+            writer.write(cipher, store, chunk).await?;
+            left -= chunk.len();
+        }
+        Ok(writer.finish(cipher, store).await?)
+    }
+
+    /**
+     * Checks if the stored value fits in the size of the entry, which it may not if the
+     * size of the entry has changed since the value was written.
+     */
+    async fn fits<S: ErrorType, M: Access<S>>(
+        &self,
+        key: &CipherKey,
+        cipher: &mut impl BlockCipher,
+        store: &mut RecordStore<S, M>,
+    ) -> Result<bool, ConfigError<S::Error>> {
+        let opened = self
+            .open(key, cipher, store)
+            .await?
+            .ok_or(ConfigError::NotSet)?;
+        Ok(opened.len <= self.max_len)
+    }
+
+    /**
+     * Finds the last record stored for the entry, and checks its CRC and authentication
+     * tag. The value is decrypted to find its length, but not given out before the tag
+     * is checked. Gives `None` if the entry has never been written.
+     */
+    async fn open<S: ErrorType, M: Access<S>>(
+        &self,
+        key: &CipherKey,
+        cipher: &mut impl BlockCipher,
+        store: &mut RecordStore<S, M>,
+    ) -> Result<Option<Opened>, ConfigError<S::Error>> {
+        let Some(record) = store.find(self.id).await? else {
+            return Ok(None);
+        };
+        let len = record.len as usize;
+        if len < NONCE_SIZE + TAG_SIZE || !(len - NONCE_SIZE - TAG_SIZE).is_multiple_of(16) {
+            return Err(ConfigError::IntegrityFailed);
+        }
+        if !store.verify(&record).await? {
+            return Err(ConfigError::IntegrityFailed);
+        }
+
+        let mut nonce = [0_u8; NONCE_SIZE];
+        store.read(&record, 0, &mut nonce).await?;
+        let n_blocks = n_blocks(&record);
 
-            let k: Key = (*key).into();
-            aes.process(&mut block, Mode::Decryption128, k);
+        let mut gcm = Gcm::new(cipher, key, &nonce, self.name.as_bytes());
+        let mut block = [0_u8; 16];
+        // text ends at the first zero, and binary values have the length at the end
+        let mut text_len = None;
+        let mut ascii = true;
+        for i in 0..n_blocks {
+            store.read(&record, block_offset(i), &mut block).await?;
+            gcm.decrypt(cipher, &mut block);
+            if text_len.is_none() {
+                let end = block.iter().position(|byte| *byte == 0);
+                ascii &= block[..end.unwrap_or(16)].is_ascii();
+                text_len = end.map(|end| 16 * i + end);
+            }
+        }
+        let mut tag = [0_u8; TAG_SIZE];
+        store
+            .read(&record, block_offset(n_blocks), &mut tag)
+            .await?;
+        if !gcm.verify(cipher, &tag) {
+            return Err(ConfigError::DecryptFailed);
+        }
 
-            if !block.is_ascii() {
-                return Err(());
+        let len = if self.is_binary() {
+            let len = u16::from_le_bytes([block[14], block[15]]) as usize;
+            if n_blocks == 0 || len > 16 * n_blocks - LEN_SIZE {
+                return Err(ConfigError::IntegrityFailed);
+            }
+            len
+        } else {
+            if !ascii {
+                return Err(ConfigError::InvalidUtf8);
             }
+            text_len.unwrap_or(16 * n_blocks)
+        };
+        Ok(Some(Opened { record, nonce, len }))
+    }
+}
 
-            for byte in block {
-                if byte == 0 {
-                    return Ok(());
-                }
-                let res = output.push(byte as char);
-                if res.is_err() {
-                    return Err(());
-                }
+/**
+ * Stored value that has been checked by `ConfigEntry::open`.
+ */
+struct Opened {
+    record: Record,
+    nonce: [u8; NONCE_SIZE],
+    len: usize,
+}
+
+/**
+ * Value being encrypted into a record a block at a time, so it does not have to be in
+ * memory as a whole.
+ */
+struct EntryWriter<'a> {
+    entry: ConfigEntry<'a>,
+    record: RecordWriter,
+    gcm: Gcm,
+    block: [u8; 16],
+    fill: usize,
+    len: usize,
+    blocks: usize,
+}
+
+impl EntryWriter<'_> {
+    async fn write<S: ErrorType, M: Access<S>>(
+        &mut self,
+        cipher: &mut impl BlockCipher,
+        store: &mut RecordStore<S, M>,
+        mut bytes: &[u8],
+    ) -> Result<(), RecordError<S::Error>> {
+        if self.len + bytes.len() > self.entry.max_len {
+            return Err(RecordError::TooLarge);
+        }
+        self.len += bytes.len();
+        while !bytes.is_empty() {
+            let n = (16 - self.fill).min(bytes.len());
+            self.block[self.fill..self.fill + n].copy_from_slice(&bytes[..n]);
+            self.fill += n;
+            bytes = &bytes[n..];
+            if self.fill == 16 {
+                self.write_block(cipher, store).await?;
             }
         }
         Ok(())
     }
+
+    /**
+     * Pads the value with zeros to the size of the entry, with the length at the end
+     * for binary values, and writes the authentication tag.
+     */
+    async fn finish<S: ErrorType, M: Access<S>>(
+        mut self,
+        cipher: &mut impl BlockCipher,
+        store: &mut RecordStore<S, M>,
+    ) -> Result<(), RecordError<S::Error>> {
+        while self.blocks < self.entry.n_blocks {
+            if self.entry.is_binary() && self.blocks == self.entry.n_blocks - 1 {
+                self.block[16 - LEN_SIZE..].copy_from_slice(&(self.len as u16).to_le_bytes());
+            }
+            self.write_block(cipher, store).await?;
+        }
+        let tag = self.gcm.finalize(cipher);
+        store.write(&mut self.record, &tag).await?;
+        store.finish(self.record).await
+    }
+
+    async fn write_block<S: ErrorType, M: Access<S>>(
+        &mut self,
+        cipher: &mut impl BlockCipher,
+        store: &mut RecordStore<S, M>,
+    ) -> Result<(), RecordError<S::Error>> {
+        self.gcm.encrypt(cipher, &mut self.block);
+        store.write(&mut self.record, &self.block).await?;
+        self.block = [0_u8; 16];
+        self.fill = 0;
+        self.blocks += 1;
+        Ok(())
+    }
+}
+
+/** Size of the length at the end of binary values. */
+const LEN_SIZE: usize = 2;
+
+/**
+ * Number of encrypted blocks in a value record.
+ */
+fn n_blocks(record: &Record) -> usize {
+    (record.len as usize - NONCE_SIZE - TAG_SIZE) / 16
+}
+
+/**
+ * Offset of an encrypted block in a value record, after the nonce.
+ */
+fn block_offset(i: usize) -> u32 {
+    (NONCE_SIZE + 16 * i) as u32
+}
+
+#[cfg(all(test, feature = "soft-crypto", not(feature = "wifi")))]
+mod tests {
+    use super::*;
+    use crate::cipher::SoftAes;
+    use crate::storage::{MemError, MemStorage};
+
+    type Mem = MemStorage<{ 4 * 4096 }>;
+    type Menu<'a> = ConfigMenu<'a, &'a mut Mem, SoftAes>;
+
+    const ENTRIES: &[ConfigEntry] = &[
+        ConfigEntry::new("value", 16, "What is this value?", false),
+        ConfigEntry::new("other", 16, "What is the other value?", false),
+        ConfigEntry::bytes("key", 32, "Which key?", true)
+            .with_constraints(&[Constraint::MinLen(16), Constraint::MaxLen(24)]),
+        ConfigEntry::new("ratio", MAX_TEXT_LEN, "Which ratio?", false).with_kind(EntryKind::F32 {
+            min: f32::MIN,
+            max: f32::MAX,
+        }),
+    ];
+    const KEY: [u8; 16] = [1; 16];
+
+    fn open<'a>(
+        mem: &'a mut Mem,
+        key: impl Into<CipherKey>,
+    ) -> Result<Menu<'a>, ConfigError<MemError>> {
+        ConfigMenu::new(ENTRIES, key, SoftAes, mem, StorageRegion::new(0, 4 * 4096))
+    }
+
+    fn read(menu: &mut Menu) -> Result<heapless::String<16>, ConfigError<MemError>> {
+        let mut value = heapless::String::new();
+        menu.read_entry("value", &mut value)?;
+        Ok(value)
+    }
+
+    /**
+     * Offset of the last record written for the entry, found by its key in the record
+     * headers.
+     */
+    fn record_offset(mem: &Mem, name: &str) -> usize {
+        let key = entry_id(name).to_le_bytes();
+        (0..mem.data.len())
+            .step_by(16)
+            .rfind(|offset| mem.data[*offset..*offset + 4] == key)
+            .unwrap()
+    }
+
+    #[test]
+    fn reports_missing_and_corrupt_values() {
+        let mut mem = Mem::new();
+        let mut menu = open(&mut mem, KEY).unwrap();
+        assert_eq!(read(&mut menu), Err(ConfigError::NotSet));
+        menu.store_entry("value", "hello").unwrap();
+        menu.store_entry("other", "world").unwrap();
+        let clean = mem.data;
+
+        // a flipped bit in the payload fails the CRC
+        let offset = record_offset(&mem, "value");
+        mem.data[offset + 16 + NONCE_SIZE] ^= 0x01;
+        let mut menu = open(&mut mem, KEY).unwrap();
+        assert_eq!(read(&mut menu), Err(ConfigError::IntegrityFailed));
+
+        // the payload and CRC of another entry pass the CRC, but not the tag, which
+        // covers the name of the entry
+        mem.data = clean;
+        let other = record_offset(&mem, "other");
+        let len = record_size(16 + NONCE_SIZE + TAG_SIZE) as usize;
+        mem.data.copy_within(other + 12..other + len, offset + 12);
+        let mut menu = open(&mut mem, KEY).unwrap();
+        assert_eq!(read(&mut menu), Err(ConfigError::DecryptFailed));
+        let mut other = heapless::String::<16>::new();
+        menu.read_entry("other", &mut other).unwrap();
+        assert_eq!(other, "world");
+    }
+
+    #[test]
+    fn rekey_changes_the_key() {
+        let mut mem = Mem::new();
+        let mut menu = open(&mut mem, KEY).unwrap();
+        menu.store_entry("value", "hello").unwrap();
+        menu.rekey(KEY, [2; 32]).unwrap();
+        assert_eq!(read(&mut menu).unwrap(), "hello");
+
+        assert_eq!(open(&mut mem, KEY).err(), Some(ConfigError::DecryptFailed));
+        let mut menu = open(&mut mem, [2; 32]).unwrap();
+        assert_eq!(read(&mut menu).unwrap(), "hello");
+    }
+
+    #[test]
+    fn rekey_checks_the_old_key_when_nothing_is_stored() {
+        let mut mem = Mem::new();
+        let mut menu = open(&mut mem, KEY).unwrap();
+        assert_eq!(
+            menu.rekey([3; 16], [2; 16]),
+            Err(ConfigError::DecryptFailed)
+        );
+
+        menu.store_entry("value", "hello").unwrap();
+        assert_eq!(read(&mut menu).unwrap(), "hello");
+        assert!(open(&mut mem, KEY).is_ok());
+    }
+
+    #[test]
+    fn rejects_a_key_of_another_size_when_opened() {
+        let short = CipherKey::Aes128([1; 16]);
+        let long = CipherKey::Aes256([1; 32]);
+        for (written, other) in [(short, long), (long, short)] {
+            let mut mem = Mem::new();
+            let mut menu = open(&mut mem, written).unwrap();
+            menu.store_entry("value", "hello").unwrap();
+
+            // the header is checked by `new`, before any value is read with the key
+            assert_eq!(
+                open(&mut mem, other).err(),
+                Some(ConfigError::DecryptFailed)
+            );
+            let mut menu = open(&mut mem, written).unwrap();
+            assert_eq!(read(&mut menu).unwrap(), "hello");
+        }
+    }
+
+    #[test]
+    fn new_or_format_recovers_from_another_key() {
+        let mut mem = Mem::new();
+        let mut menu = open(&mut mem, KEY).unwrap();
+        menu.store_entry("value", "hello").unwrap();
+
+        assert_eq!(
+            open(&mut mem, [2; 16]).err(),
+            Some(ConfigError::DecryptFailed)
+        );
+        let region = StorageRegion::new(0, 4 * 4096);
+        let mut menu =
+            ConfigMenu::new_or_format(ENTRIES, [2; 16], SoftAes, &mut mem, region).unwrap();
+        assert_eq!(read(&mut menu), Err(ConfigError::NotSet));
+        menu.store_entry("value", "again").unwrap();
+
+        let mut menu = open(&mut mem, [2; 16]).unwrap();
+        assert_eq!(read(&mut menu).unwrap(), "again");
+    }
+
+    #[test]
+    fn restores_defaults() {
+        const DEFAULTS: &[ConfigEntry] = &[
+            ConfigEntry::new("port", 8, "Which port?", false)
+                .with_kind(EntryKind::U32 { min: 1, max: 65535 })
+                .with_default("8080"),
+            ConfigEntry::new("value", 16, "What is this value?", false),
+        ];
+        let region = StorageRegion::new(0, 4 * 4096);
+        let mut mem = Mem::new();
+        let mut menu = ConfigMenu::new(DEFAULTS, KEY, SoftAes, &mut mem, region).unwrap();
+        assert_eq!(menu.get_u32("port"), Ok(8080));
+        assert_eq!(read(&mut menu), Err(ConfigError::NotSet));
+
+        menu.store_entry("port", "9090").unwrap();
+        menu.store_entry("value", "hello").unwrap();
+        assert_eq!(menu.get_u32("port"), Ok(9090));
+        menu.restore_default("port").unwrap();
+        assert_eq!(menu.get_u32("port"), Ok(8080));
+        assert_eq!(read(&mut menu).unwrap(), "hello");
+        menu.restore_default("value").unwrap();
+        assert_eq!(read(&mut menu), Err(ConfigError::NotSet));
+
+        menu.store_entry("port", "9090").unwrap();
+        menu.store_entry("value", "hello").unwrap();
+        menu.restore_defaults().unwrap();
+        let mut menu = ConfigMenu::new(DEFAULTS, KEY, SoftAes, &mut mem, region).unwrap();
+        assert_eq!(menu.get_u32("port"), Ok(8080));
+        assert_eq!(read(&mut menu), Err(ConfigError::NotSet));
+    }
+
+    #[test]
+    fn migrates_values_to_changed_entries() {
+        const CHANGED: &[ConfigEntry] = &[
+            ConfigEntry::new("value", 16, "What is this value?", false),
+            ConfigEntry::new("other", 40, "What is the other value?", false),
+            ConfigEntry::bytes("key", 8, "Which key?", true),
+        ];
+        let region = StorageRegion::new(0, 4 * 4096);
+        let mut mem = Mem::new();
+        let mut menu = open(&mut mem, KEY).unwrap();
+        menu.store_entry("value", "hello").unwrap();
+        menu.store_entry("other", "world").unwrap();
+        menu.store_bytes("key", &[7; 16]).unwrap();
+        menu.store_entry("ratio", "0.5").unwrap();
+
+        // "other" is written again in its new size, and "key" no longer fits
+        let mut menu = ConfigMenu::new(CHANGED, KEY, SoftAes, &mut mem, region).unwrap();
+        assert_eq!(read(&mut menu).unwrap(), "hello");
+        let mut other = heapless::String::<40>::new();
+        menu.read_entry("other", &mut other).unwrap();
+        assert_eq!(other, "world");
+        let mut key = [0; 8];
+        assert_eq!(menu.read_bytes("key", &mut key), Err(ConfigError::NotSet));
+        let other_id = entry_id("other");
+        assert_eq!(
+            block_on(menu.store.find(other_id)).unwrap().unwrap().len as usize,
+            CHANGED[1].size()
+        );
+
+        // the value of "ratio" was removed with its entry
+        let mut menu = open(&mut mem, KEY).unwrap();
+        assert_eq!(menu.get_f32("ratio"), Err(ConfigError::NotSet));
+        assert_eq!(read(&mut menu).unwrap(), "hello");
+    }
+
+    #[test]
+    fn migration_removes_values_longer_than_the_new_max_len() {
+        const SHORTER: &[ConfigEntry] = &[
+            ConfigEntry::new("value", 4, "What is this value?", false),
+            ConfigEntry::new("other", 8, "What is the other value?", false),
+        ];
+        let region = StorageRegion::new(0, 4 * 4096);
+        let mut mem = Mem::new();
+        let mut menu = open(&mut mem, KEY).unwrap();
+        menu.store_entry("value", "hello").unwrap();
+        menu.store_entry("other", "world").unwrap();
+
+        // both entries still take one block, so only the length tells
+        let mut menu = ConfigMenu::new(SHORTER, KEY, SoftAes, &mut mem, region).unwrap();
+        assert_eq!(read(&mut menu), Err(ConfigError::NotSet));
+        let mut other = heapless::String::<8>::new();
+        menu.read_entry("other", &mut other).unwrap();
+        assert_eq!(other, "world");
+    }
+
+    #[test]
+    fn migration_removes_more_entries_than_a_batch() {
+        const NAMES: [&str; 2 * REMOVED_BATCH + 3] = [
+            "e0", "e1", "e2", "e3", "e4", "e5", "e6", "e7", "e8", "e9", "e10", "e11", "e12", "e13",
+            "e14", "e15", "e16", "e17", "e18", "e19", "e20", "e21", "e22", "e23", "e24", "e25",
+            "e26", "e27", "e28", "e29", "e30", "e31", "e32", "e33", "e34",
+        ];
+        let many: [ConfigEntry; NAMES.len()] =
+            core::array::from_fn(|i| ConfigEntry::new(NAMES[i], 16, "Which value?", false));
+        let region = StorageRegion::new(0, 4 * 4096);
+        let mut mem = Mem::new();
+        let mut menu = ConfigMenu::new(&many, KEY, SoftAes, &mut mem, region).unwrap();
+        for name in NAMES {
+            menu.store_entry(name, name).unwrap();
+        }
+
+        open(&mut mem, KEY).unwrap();
+        let mut menu = ConfigMenu::new(&many, KEY, SoftAes, &mut mem, region).unwrap();
+        for name in NAMES {
+            let mut value = heapless::String::<16>::new();
+            assert_eq!(
+                menu.read_entry(name, &mut value),
+                Err(ConfigError::NotSet),
+                "{name}"
+            );
+        }
+    }
+
+    #[test]
+    fn checks_the_length_of_binary_values() {
+        let mut mem = Mem::new();
+        let mut menu = open(&mut mem, KEY).unwrap();
+        let short = Err(ConfigError::Invalid("too short"));
+        assert_eq!(menu.store_bytes("key", &[1; 15]), short);
+        assert_eq!(menu.store_values(&[("key", &[1; 15])]), short);
+        assert_eq!(
+            menu.store_bytes("key", &[1; 25]),
+            Err(ConfigError::Invalid("too long"))
+        );
+        menu.store_bytes("key", &[1; 16]).unwrap();
+
+        let mut writer = menu.begin_value("key").unwrap();
+        menu.write_value(&mut writer, &[2; 8]).unwrap();
+        assert_eq!(menu.finish_value(writer), short);
+        let mut key = [0; 32];
+        assert_eq!(menu.read_bytes("key", &mut key), Ok(16));
+    }
+
+    #[test]
+    fn binary_values_are_limited_to_the_declared_length() {
+        const BINARY: &[ConfigEntry] = &[ConfigEntry::bytes("key", 32, "Which key?", true)];
+        let region = StorageRegion::new(0, 4 * 4096);
+        let mut mem = Mem::new();
+        let mut menu = ConfigMenu::new(BINARY, KEY, SoftAes, &mut mem, region).unwrap();
+        // the 32 bytes and the length take three blocks, which would hold 46 bytes
+        assert_eq!(BINARY[0].n_blocks, 3);
+        menu.store_bytes("key", &[1; 32]).unwrap();
+        assert_eq!(menu.store_bytes("key", &[2; 33]), Err(ConfigError::TooLong));
+        assert_eq!(
+            menu.store_values(&[("key", &[2; 33])]),
+            Err(ConfigError::TooLong)
+        );
+        let mut writer = menu.begin_value("key").unwrap();
+        menu.write_value(&mut writer, &[2; 32]).unwrap();
+        assert_eq!(
+            menu.write_value(&mut writer, &[2]),
+            Err(ConfigError::TooLong)
+        );
+
+        let mut key = [0; 46];
+        assert_eq!(menu.read_bytes("key", &mut key), Ok(32));
+        assert_eq!(key[..32], [1; 32]);
+    }
+
+    #[test]
+    fn rejects_zero_bytes_in_text() {
+        let mut mem = Mem::new();
+        let mut menu = open(&mut mem, KEY).unwrap();
+        let invalid = Err(ConfigError::Invalid("not ASCII"));
+        assert_eq!(menu.store_entry("value", "a\0b"), invalid);
+        assert_eq!(menu.store_entries(&[("value", "a\0b")]), invalid);
+        assert_eq!(menu.store_values(&[("value", b"a\0b")]), invalid);
+        assert_eq!(read(&mut menu), Err(ConfigError::NotSet));
+    }
+
+    #[test]
+    fn rejects_text_constraints_on_binary_entries() {
+        let entries = [ConfigEntry::bytes("key", 32, "Which key?", true)
+            .with_constraints(&[Constraint::Chars("abc")])];
+        assert_eq!(
+            check_layout(&entries),
+            Err(LayoutError::TextConstraint("key"))
+        );
+    }
+
+    #[test]
+    fn checks_the_layout() {
+        let entry = |name| ConfigEntry::new(name, 16, "?", false);
+        assert_eq!(check_layout(ENTRIES), Ok(()));
+        assert_eq!(check_layout(&[entry("")]), Err(LayoutError::EmptyName));
+        assert_eq!(
+            check_layout(&[entry("a"), entry("b"), entry("a")]),
+            Err(LayoutError::DuplicateName("a"))
+        );
+        assert_eq!(
+            check_layout(&[ConfigEntry::new("a", 0, "?", false)]),
+            Err(LayoutError::ZeroSize("a"))
+        );
+        assert_eq!(
+            check_layout(&[ConfigEntry::bytes("a", 0, "?", false)]),
+            Err(LayoutError::ZeroSize("a"))
+        );
+        assert_eq!(
+            check_layout(&[ConfigEntry::bytes("a", u16::MAX as usize, "?", false)]),
+            Err(LayoutError::TooLarge("a"))
+        );
+        // Two names with the same FNV-1a hash.
+        assert_eq!(entry_id("glbvs"), entry_id("yacxa"));
+        assert_eq!(
+            check_layout(&[entry("glbvs"), entry("yacxa")]),
+            Err(LayoutError::SameId("glbvs", "yacxa"))
+        );
+    }
+
+    #[test]
+    fn checks_the_layout_against_reserved_names() {
+        let entry = |name| ConfigEntry::new(name, 16, "?", false);
+        let reserved = ["wifi_ssid", "yacxa"];
+        assert_eq!(check_layout_with(&[entry("value")], &reserved), Ok(()));
+        assert_eq!(
+            check_layout_with(&[entry("value"), entry("wifi_ssid")], &reserved),
+            Err(LayoutError::ReservedName("wifi_ssid"))
+        );
+        assert_eq!(
+            check_layout_with(&[entry("glbvs")], &reserved),
+            Err(LayoutError::SameId("glbvs", "yacxa"))
+        );
+    }
+
+    #[test]
+    fn f32_extremes_round_trip() {
+        let mut mem = Mem::new();
+        let mut menu = open(&mut mem, KEY).unwrap();
+        let smallest = f32::from_bits(1);
+        for value in [
+            f32::MAX,
+            f32::MIN,
+            f32::MIN_POSITIVE,
+            -f32::MIN_POSITIVE,
+            smallest,
+            -smallest,
+        ] {
+            let text = crate::declare::__private::to_text::<MemError>(value).unwrap();
+            menu.store_entry("ratio", &text).unwrap();
+            assert_eq!(menu.get_f32("ratio"), Ok(value), "{text}");
+        }
+    }
+
+    #[test]
+    fn async_menu_reads_typed_values() {
+        let mut mem = Mem::new();
+        block_on(async {
+            let region = StorageRegion::new(0, 4 * 4096);
+            let mut menu = ConfigMenu::new_async(ENTRIES, KEY, SoftAes, &mut mem, region)
+                .await
+                .unwrap();
+            let ratio = crate::declare::Entry::<crate::declare::kind::F32>::new("ratio");
+            ratio.set_async(&mut menu, 0.5).await.unwrap();
+            assert_eq!(menu.get_f32_async("ratio").await, Ok(0.5));
+            assert_eq!(
+                menu.get_u32_async("ratio").await,
+                Err(ConfigError::WrongType)
+            );
+        });
+        let mut menu = open(&mut mem, KEY).unwrap();
+        assert_eq!(menu.get_f32("ratio"), Ok(0.5));
+    }
+
+    #[test]
+    fn rekey_passphrase_needs_a_key_deriver() {
+        let mut mem = Mem::new();
+        let mut menu = open(&mut mem, KEY).unwrap();
+        assert_eq!(
+            menu.rekey_passphrase("old", "new"),
+            Err(ConfigError::NoKeyDeriver)
+        );
+    }
 }
@@ -0,0 +1,781 @@
+use crate::cipher::BlockCipher;
+use crate::configs::{ConfigEntry, ConfigError, ConfigMenu};
+use crate::storage::Access;
+use core::fmt::Display;
+use core::future::Future;
+use core::marker::PhantomData;
+use core::net::Ipv4Addr;
+use embassy_futures::block_on;
+use embedded_storage::nor_flash::{ErrorType, NorFlash};
+
+/**
+ * Declares the config entries of an application, and generates a struct with the
+ * `ConfigMenu` and a typed `Entry` for each entry, so a misspelled entry name is a
+ * compile error.
+ *
+ * Each entry is given as `name: Kind[max_len], "question"`, optionally followed by
+ * `, secret = true` and `, default = "value"`. The kinds are the ones of
+ * `value::EntryKind`: `Text`, `U32(min, max)`, `I32(min, max)`, `Bool`,
+ * `Choice("a", "b", ...)`, `Ipv4`, `F32(min, max)` and `Bytes`.
+ *
+ * ```ignore
+ * declare_config! {
+ *     /** Settings of the app. */
+ *     pub struct AppConfig {
+ *         host: Text[64], "Which host should be used?", default = "example.com";
+ *         port: U32(1, 65535)[16], "Which port should be used?", default = "80";
+ *         token: Text[48], "What is the API token?", secret = true;
+ *     }
+ * }
+ *
+ * let config = AppConfig::init(key, aes, flash, region).unwrap();
+ * config_init(spawner, config.menu, uart_rx, uart_tx).await;
+ *
+ * let mut menu = config.menu.lock().await;
+ * let port = config.port.get(&mut menu)?;
+ * ```
+ *
+ * The struct has `ENTRIES`, the table of `ConfigEntry`s for `ConfigMenu::new` and
+ * `required_len`, and `init`, which takes the arguments of `ConfigMenu::new` after the
+ * entries and keeps the menu in a static. `init_or_format` is the same for
+ * `ConfigMenu::new_or_format`. They fail if the menu has already been made.
+ * The entries are checked with `check_layout` when building.
+ *
+ * `init` and `init_or_format` make a menu on the internal flash. For a menu on other
+ * storage, or made with `ConfigMenu::new_async`, the type of the menu is given after the
+ * name of the struct, and the menu made from `ENTRIES` is kept with `init_menu`:
+ *
+ * ```ignore
+ * declare_config! {
+ *     pub struct AppConfig: ConfigMenu<'static, SpiFlash, Aes<'static>, Async> {
+ *         port: U32(1, 65535)[16], "Which port should be used?", default = "80";
+ *     }
+ * }
+ *
+ * let menu = ConfigMenu::new_async(AppConfig::ENTRIES, key, aes, flash, region).await?;
+ * let config = AppConfig::init_menu(menu)?;
+ *
+ * let mut menu = config.menu.lock().await;
+ * let port = config.port.get_async(&mut menu).await?;
+ * ```
+ */
+#[macro_export]
+macro_rules! declare_config {
+    (
+        $(#[$meta:meta])*
+        $vis:vis struct $config:ident { $($entries:tt)* }
+    ) => {
+        $crate::declare_config! {
+            $(#[$meta])*
+            $vis struct $config: $crate::declare::__private::DefaultMenu { $($entries)* }
+        }
+
+        impl $config {
+            $crate::__config_init!();
+        }
+    };
+    (
+        $(#[$meta:meta])*
+        $vis:vis struct $config:ident: $menu:ty {
+            $(
+                $(#[$entry_meta:meta])*
+                $name:ident : $kind:ident $( ( $($arg:expr),* $(,)? ) )? [$len:expr], $question:literal
+                    $(, secret = $secret:expr)?
+                    $(, default = $default:literal)?
+                ;
+            )*
+        }
+    ) => {
+        $(#[$meta])*
+        #[derive(Clone, Copy)]
+        $vis struct $config {
+            pub menu: &'static $crate::declare::__private::ConfigMutex<$menu>,
+            $(
+                $(#[$entry_meta])*
+                pub $name: $crate::declare::Entry<$crate::declare::kind::$kind>,
+            )*
+        }
+
+        impl $config {
+            /** The config entries, in the order they were declared. */
+            pub const ENTRIES: &'static [$crate::configs::ConfigEntry<'static>] = &[
+                $(
+                    $crate::__config_entry!(
+                        $kind $( ( $($arg),* ) )?,
+                        stringify!($name),
+                        $len,
+                        $question,
+                        false $(|| $secret)?
+                    )
+                    $(.with_default($default))?
+                ),*
+            ];
+
+            /** Typed entries for a menu made from `ENTRIES`. */
+            pub fn new(menu: &'static $crate::declare::__private::ConfigMutex<$menu>) -> Self {
+                Self {
+                    menu,
+                    $($name: $crate::declare::Entry::new(stringify!($name)),)*
+                }
+            }
+
+            /**
+             * Keeps a menu made from `ENTRIES` in a static. Fails if a menu has already
+             * been kept, also by `init` or `init_or_format`.
+             */
+            pub fn init_menu(
+                menu: $menu,
+            ) -> Result<
+                Self,
+                $crate::configs::ConfigError<$crate::declare::__private::MenuError<$menu>>,
+            > {
+                static MENU: $crate::declare::__private::StaticCell<
+                    $crate::declare::__private::ConfigMutex<$menu>,
+                > = $crate::declare::__private::StaticCell::new();
+                let menu = MENU
+                    .try_init($crate::declare::__private::ConfigMutex::new(menu))
+                    .ok_or($crate::configs::ConfigError::Invalid("already initialized"))?;
+                Ok(Self::new(menu))
+            }
+        }
+
+        // mistakes in the entries, like duplicate names, fail the build
+        const _: () = if let Err(err) = $crate::configs::check_layout($config::ENTRIES) {
+            panic!("{}", err.message())
+        };
+    };
+}
+
+/**
+ * Makes the `ConfigEntry` of an entry declared with `declare_config!`.
+ */
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __config_entry {
+    (Bytes, $name:expr, $len:expr, $question:expr, $secret:expr) => {
+        $crate::configs::ConfigEntry::bytes($name, $len, $question, $secret)
+    };
+    (Text, $name:expr, $len:expr, $question:expr, $secret:expr) => {
+        $crate::configs::ConfigEntry::new($name, $len, $question, $secret)
+    };
+    (Bool, $name:expr, $len:expr, $question:expr, $secret:expr) => {
+        $crate::configs::ConfigEntry::new($name, $len, $question, $secret)
+            .with_kind($crate::value::EntryKind::Bool)
+    };
+    (Ipv4, $name:expr, $len:expr, $question:expr, $secret:expr) => {
+        $crate::configs::ConfigEntry::new($name, $len, $question, $secret)
+            .with_kind($crate::value::EntryKind::Ipv4)
+    };
+    (Choice($($choice:expr),*), $name:expr, $len:expr, $question:expr, $secret:expr) => {
+        $crate::configs::ConfigEntry::new($name, $len, $question, $secret)
+            .with_kind($crate::value::EntryKind::Choice(&[$($choice),*]))
+    };
+    ($kind:ident($min:expr, $max:expr), $name:expr, $len:expr, $question:expr, $secret:expr) => {
+        $crate::configs::ConfigEntry::new($name, $len, $question, $secret)
+            .with_kind($crate::value::EntryKind::$kind { min: $min, max: $max })
+    };
+}
+
+/**
+ * Makes `init` of a struct declared with `declare_config!`. It is declared here, so it
+ * takes the wifi sender when this crate is built with the wifi feature.
+ */
+#[cfg(feature = "wifi")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __config_init {
+    () => {
+        /**
+         * Creates the `ConfigMenu` for the entries, like `ConfigMenu::new`, and keeps it
+         * in a static.
+         */
+        pub fn init(
+            key: impl Into<$crate::cipher::CipherKey>,
+            cipher: $crate::declare::__private::Aes<'static>,
+            storage: $crate::declare::__private::FlashStorage,
+            region: $crate::storage::StorageRegion,
+            wifi_sender: $crate::declare::__private::WifiSender,
+        ) -> Result<Self, $crate::configs::ConfigError<$crate::declare::__private::FlashStorageError>> {
+            let menu = $crate::configs::ConfigMenu::new(
+                Self::ENTRIES,
+                key,
+                cipher,
+                storage,
+                region,
+                wifi_sender,
+            )?;
+            Self::init_menu(menu)
+        }
+
+        /**
+         * Creates the `ConfigMenu` like `ConfigMenu::new_or_format`, so the storage is
+         * formatted if it can not be opened, and keeps it in a static.
+         */
+        pub fn init_or_format(
+            key: impl Into<$crate::cipher::CipherKey>,
+            cipher: $crate::declare::__private::Aes<'static>,
+            storage: $crate::declare::__private::FlashStorage,
+            region: $crate::storage::StorageRegion,
+            wifi_sender: $crate::declare::__private::WifiSender,
+        ) -> Result<Self, $crate::configs::ConfigError<$crate::declare::__private::FlashStorageError>> {
+            let menu = $crate::configs::ConfigMenu::new_or_format(
+                Self::ENTRIES,
+                key,
+                cipher,
+                storage,
+                region,
+                wifi_sender,
+            )?;
+            Self::init_menu(menu)
+        }
+    };
+}
+
+#[cfg(not(feature = "wifi"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __config_init {
+    () => {
+        /**
+         * Creates the `ConfigMenu` for the entries, like `ConfigMenu::new`, and keeps it
+         * in a static.
+         */
+        pub fn init(
+            key: impl Into<$crate::cipher::CipherKey>,
+            cipher: $crate::declare::__private::Aes<'static>,
+            storage: $crate::declare::__private::FlashStorage,
+            region: $crate::storage::StorageRegion,
+        ) -> Result<Self, $crate::configs::ConfigError<$crate::declare::__private::FlashStorageError>> {
+            let menu =
+                $crate::configs::ConfigMenu::new(Self::ENTRIES, key, cipher, storage, region)?;
+            Self::init_menu(menu)
+        }
+
+        /**
+         * Creates the `ConfigMenu` like `ConfigMenu::new_or_format`, so the storage is
+         * formatted if it can not be opened, and keeps it in a static.
+         */
+        pub fn init_or_format(
+            key: impl Into<$crate::cipher::CipherKey>,
+            cipher: $crate::declare::__private::Aes<'static>,
+            storage: $crate::declare::__private::FlashStorage,
+            region: $crate::storage::StorageRegion,
+        ) -> Result<Self, $crate::configs::ConfigError<$crate::declare::__private::FlashStorageError>> {
+            let menu = $crate::configs::ConfigMenu::new_or_format(
+                Self::ENTRIES,
+                key,
+                cipher,
+                storage,
+                region,
+            )?;
+            Self::init_menu(menu)
+        }
+    };
+}
+
+/**
+ * Config described as a struct, with an entry for each field, which is read and stored
+ * as a whole. Usually derived with `#[derive(Config)]`, with the `derive` feature:
+ *
+ * ```ignore
+ * #[derive(Config)]
+ * struct AppConfig {
+ *     /** Which MQTT host should be used? */
+ *     #[config(default = "localhost")]
+ *     mqtt_host: heapless::String<64>,
+ *     #[config(question = "Which MQTT port?", min = 1, default = "1883")]
+ *     mqtt_port: u16,
+ *     #[config(secret)]
+ *     api_token: heapless::String<48>,
+ * }
+ *
+ * let menu = ConfigMenu::new(AppConfig::ENTRIES, key, aes, flash, region)?;
+ * let config: AppConfig = menu.load()?;
+ * ```
+ *
+ * Menus made with `ConfigMenu::new_async` read and store the struct with `load_async` and
+ * `save_async`.
+ *
+ * The fields can be `heapless::String<N>` (text of up to N characters),
+ * `heapless::Vec<u8, N>` (binary), `u8`, `u16`, `u32`, `i8`, `i16`, `i32`, `f32`, `bool`,
+ * `Ipv4Addr`, or `usize` with `choices("a", "b", ...)`, the index of the choice. The
+ * entry is named after the field. The `config` attribute can set the `name`, `question`
+ * (the doc comment of the field by default), `max_len`, `min`, `max`, `default`, and
+ * `secret`.
+ */
+pub trait Config: Sized {
+    /** The entries of the fields, in order. */
+    const ENTRIES: &'static [ConfigEntry<'static>];
+
+    /**
+     * Reads all the fields. Fails if a field can not be read, or has never been written
+     * and has no default.
+     */
+    fn load_async<S: ErrorType, C: BlockCipher, M: Access<S>>(
+        menu: &mut ConfigMenu<'_, S, C, M>,
+    ) -> impl Future<Output = Result<Self, ConfigError<S::Error>>>;
+
+    /**
+     * Stores all the fields, in one transaction.
+     */
+    fn save_async<S: ErrorType, C: BlockCipher, M: Access<S>>(
+        &self,
+        menu: &mut ConfigMenu<'_, S, C, M>,
+    ) -> impl Future<Output = Result<(), ConfigError<S::Error>>>;
+
+    /**
+     * Blocking version of `load_async`.
+     */
+    fn load<S: NorFlash, C: BlockCipher>(
+        menu: &mut ConfigMenu<'_, S, C>,
+    ) -> Result<Self, ConfigError<S::Error>> {
+        block_on(Self::load_async(menu))
+    }
+
+    /**
+     * Blocking version of `save_async`.
+     */
+    fn save<S: NorFlash, C: BlockCipher>(
+        &self,
+        menu: &mut ConfigMenu<'_, S, C>,
+    ) -> Result<(), ConfigError<S::Error>> {
+        block_on(self.save_async(menu))
+    }
+}
+
+#[cfg(feature = "derive")]
+pub use esp_embassy_config_derive::Config;
+
+/**
+ * Types used by the code generated by `declare_config!` and `#[derive(Config)]`.
+ */
+#[doc(hidden)]
+pub mod __private {
+    use crate::configs::{ConfigError, ConfigMenu};
+    use crate::value::MAX_TEXT_LEN;
+    use core::fmt::{Display, Write};
+    use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+    use embassy_sync::mutex::Mutex;
+
+    pub use embedded_storage::nor_flash::ErrorType;
+    #[cfg(feature = "esp")]
+    pub use esp_hal::aes::Aes;
+    #[cfg(feature = "esp")]
+    pub use esp_storage::{FlashStorage, FlashStorageError};
+    pub use static_cell::StaticCell;
+
+    pub type ConfigMutex<Menu> = Mutex<CriticalSectionRawMutex, Menu>;
+
+    /** Menu of a config declared without a menu type, on the internal flash. */
+    #[cfg(feature = "esp")]
+    pub type DefaultMenu = ConfigMenu<'static>;
+
+    /**
+     * Storage error of a menu.
+     */
+    pub type MenuError<Menu> = <Menu as MenuStorage>::Error;
+
+    pub trait MenuStorage {
+        type Error;
+    }
+
+    impl<S: ErrorType, C, M> MenuStorage for ConfigMenu<'_, S, C, M> {
+        type Error = S::Error;
+    }
+
+    /**
+     * Text a value is stored as.
+     */
+    pub fn to_text<E>(
+        value: impl Display,
+    ) -> Result<heapless::String<MAX_TEXT_LEN>, ConfigError<E>> {
+        let mut text = heapless::String::new();
+        write!(text, "{}", value).map_err(|_| ConfigError::TooLong)?;
+        Ok(text)
+    }
+    #[cfg(feature = "wifi")]
+    pub type WifiSender = embassy_sync::channel::Sender<
+        'static,
+        CriticalSectionRawMutex,
+        esp_wifi::wifi::ClientConfiguration,
+        1,
+    >;
+}
+
+/**
+ * Kinds of entries, for the typed `Entry`s made by `declare_config!`.
+ */
+pub mod kind {
+    pub struct Text;
+    pub struct U32;
+    pub struct I32;
+    pub struct Bool;
+    pub struct Choice;
+    pub struct Ipv4;
+    pub struct F32;
+    pub struct Bytes;
+}
+
+/**
+ * Entry of a config declared with `declare_config!`, with getters and setters for the
+ * kind of the entry. The `_async` versions are for menus made with `ConfigMenu::new_async`.
+ */
+pub struct Entry<K> {
+    name: &'static str,
+    kind: PhantomData<K>,
+}
+
+impl<K> Clone for Entry<K> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<K> Copy for Entry<K> {}
+
+impl<K> Entry<K> {
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            kind: PhantomData,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /**
+     * Restores the default of the entry, see `ConfigMenu::restore_default`.
+     */
+    pub fn restore_default<S: NorFlash, C: BlockCipher>(
+        &self,
+        menu: &mut ConfigMenu<'_, S, C>,
+    ) -> Result<(), ConfigError<S::Error>> {
+        block_on(self.restore_default_async(menu))
+    }
+
+    pub async fn restore_default_async<S: ErrorType, C: BlockCipher, M: Access<S>>(
+        &self,
+        menu: &mut ConfigMenu<'_, S, C, M>,
+    ) -> Result<(), ConfigError<S::Error>> {
+        menu.restore_default_async(self.name).await
+    }
+}
+
+impl Entry<kind::Text> {
+    pub fn get<S: NorFlash, C: BlockCipher, const N: usize>(
+        &self,
+        menu: &mut ConfigMenu<'_, S, C>,
+        output: &mut heapless::String<N>,
+    ) -> Result<(), ConfigError<S::Error>> {
+        block_on(self.get_async(menu, output))
+    }
+
+    pub async fn get_async<S: ErrorType, C: BlockCipher, M: Access<S>, const N: usize>(
+        &self,
+        menu: &mut ConfigMenu<'_, S, C, M>,
+        output: &mut heapless::String<N>,
+    ) -> Result<(), ConfigError<S::Error>> {
+        menu.read_entry_async(self.name, output).await
+    }
+
+    /**
+     * Reads the value in parts, see `ConfigMenu::read_entry_with`.
+     */
+    pub fn get_with<S: NorFlash, C: BlockCipher>(
+        &self,
+        menu: &mut ConfigMenu<'_, S, C>,
+        f: impl FnMut(&[u8]),
+    ) -> Result<usize, ConfigError<S::Error>> {
+        block_on(self.get_with_async(menu, f))
+    }
+
+    pub async fn get_with_async<S: ErrorType, C: BlockCipher, M: Access<S>>(
+        &self,
+        menu: &mut ConfigMenu<'_, S, C, M>,
+        f: impl FnMut(&[u8]),
+    ) -> Result<usize, ConfigError<S::Error>> {
+        menu.read_entry_with_async(self.name, f).await
+    }
+
+    pub fn set<S: NorFlash, C: BlockCipher>(
+        &self,
+        menu: &mut ConfigMenu<'_, S, C>,
+        value: &str,
+    ) -> Result<(), ConfigError<S::Error>> {
+        block_on(self.set_async(menu, value))
+    }
+
+    pub async fn set_async<S: ErrorType, C: BlockCipher, M: Access<S>>(
+        &self,
+        menu: &mut ConfigMenu<'_, S, C, M>,
+        value: &str,
+    ) -> Result<(), ConfigError<S::Error>> {
+        menu.store_entry_async(self.name, value).await
+    }
+}
+
+impl Entry<kind::U32> {
+    pub fn get<S: NorFlash, C: BlockCipher>(
+        &self,
+        menu: &mut ConfigMenu<'_, S, C>,
+    ) -> Result<u32, ConfigError<S::Error>> {
+        block_on(self.get_async(menu))
+    }
+
+    pub async fn get_async<S: ErrorType, C: BlockCipher, M: Access<S>>(
+        &self,
+        menu: &mut ConfigMenu<'_, S, C, M>,
+    ) -> Result<u32, ConfigError<S::Error>> {
+        menu.get_u32_async(self.name).await
+    }
+
+    pub fn set<S: NorFlash, C: BlockCipher>(
+        &self,
+        menu: &mut ConfigMenu<'_, S, C>,
+        value: u32,
+    ) -> Result<(), ConfigError<S::Error>> {
+        block_on(self.set_async(menu, value))
+    }
+
+    pub async fn set_async<S: ErrorType, C: BlockCipher, M: Access<S>>(
+        &self,
+        menu: &mut ConfigMenu<'_, S, C, M>,
+        value: u32,
+    ) -> Result<(), ConfigError<S::Error>> {
+        store_display(menu, self.name, value).await
+    }
+}
+
+impl Entry<kind::I32> {
+    pub fn get<S: NorFlash, C: BlockCipher>(
+        &self,
+        menu: &mut ConfigMenu<'_, S, C>,
+    ) -> Result<i32, ConfigError<S::Error>> {
+        block_on(self.get_async(menu))
+    }
+
+    pub async fn get_async<S: ErrorType, C: BlockCipher, M: Access<S>>(
+        &self,
+        menu: &mut ConfigMenu<'_, S, C, M>,
+    ) -> Result<i32, ConfigError<S::Error>> {
+        menu.get_i32_async(self.name).await
+    }
+
+    pub fn set<S: NorFlash, C: BlockCipher>(
+        &self,
+        menu: &mut ConfigMenu<'_, S, C>,
+        value: i32,
+    ) -> Result<(), ConfigError<S::Error>> {
+        block_on(self.set_async(menu, value))
+    }
+
+    pub async fn set_async<S: ErrorType, C: BlockCipher, M: Access<S>>(
+        &self,
+        menu: &mut ConfigMenu<'_, S, C, M>,
+        value: i32,
+    ) -> Result<(), ConfigError<S::Error>> {
+        store_display(menu, self.name, value).await
+    }
+}
+
+impl Entry<kind::Bool> {
+    pub fn get<S: NorFlash, C: BlockCipher>(
+        &self,
+        menu: &mut ConfigMenu<'_, S, C>,
+    ) -> Result<bool, ConfigError<S::Error>> {
+        block_on(self.get_async(menu))
+    }
+
+    pub async fn get_async<S: ErrorType, C: BlockCipher, M: Access<S>>(
+        &self,
+        menu: &mut ConfigMenu<'_, S, C, M>,
+    ) -> Result<bool, ConfigError<S::Error>> {
+        menu.get_bool_async(self.name).await
+    }
+
+    pub fn set<S: NorFlash, C: BlockCipher>(
+        &self,
+        menu: &mut ConfigMenu<'_, S, C>,
+        value: bool,
+    ) -> Result<(), ConfigError<S::Error>> {
+        block_on(self.set_async(menu, value))
+    }
+
+    pub async fn set_async<S: ErrorType, C: BlockCipher, M: Access<S>>(
+        &self,
+        menu: &mut ConfigMenu<'_, S, C, M>,
+        value: bool,
+    ) -> Result<(), ConfigError<S::Error>> {
+        menu.store_entry_async(self.name, if value { "yes" } else { "no" })
+            .await
+    }
+}
+
+impl Entry<kind::Choice> {
+    /**
+     * Reads the index of the choice.
+     */
+    pub fn get<S: NorFlash, C: BlockCipher>(
+        &self,
+        menu: &mut ConfigMenu<'_, S, C>,
+    ) -> Result<usize, ConfigError<S::Error>> {
+        block_on(self.get_async(menu))
+    }
+
+    pub async fn get_async<S: ErrorType, C: BlockCipher, M: Access<S>>(
+        &self,
+        menu: &mut ConfigMenu<'_, S, C, M>,
+    ) -> Result<usize, ConfigError<S::Error>> {
+        menu.get_choice_async(self.name).await
+    }
+
+    pub fn set<S: NorFlash, C: BlockCipher>(
+        &self,
+        menu: &mut ConfigMenu<'_, S, C>,
+        choice: &str,
+    ) -> Result<(), ConfigError<S::Error>> {
+        block_on(self.set_async(menu, choice))
+    }
+
+    pub async fn set_async<S: ErrorType, C: BlockCipher, M: Access<S>>(
+        &self,
+        menu: &mut ConfigMenu<'_, S, C, M>,
+        choice: &str,
+    ) -> Result<(), ConfigError<S::Error>> {
+        menu.store_entry_async(self.name, choice).await
+    }
+}
+
+impl Entry<kind::Ipv4> {
+    pub fn get<S: NorFlash, C: BlockCipher>(
+        &self,
+        menu: &mut ConfigMenu<'_, S, C>,
+    ) -> Result<Ipv4Addr, ConfigError<S::Error>> {
+        block_on(self.get_async(menu))
+    }
+
+    pub async fn get_async<S: ErrorType, C: BlockCipher, M: Access<S>>(
+        &self,
+        menu: &mut ConfigMenu<'_, S, C, M>,
+    ) -> Result<Ipv4Addr, ConfigError<S::Error>> {
+        menu.get_ipv4_async(self.name).await
+    }
+
+    pub fn set<S: NorFlash, C: BlockCipher>(
+        &self,
+        menu: &mut ConfigMenu<'_, S, C>,
+        value: Ipv4Addr,
+    ) -> Result<(), ConfigError<S::Error>> {
+        block_on(self.set_async(menu, value))
+    }
+
+    pub async fn set_async<S: ErrorType, C: BlockCipher, M: Access<S>>(
+        &self,
+        menu: &mut ConfigMenu<'_, S, C, M>,
+        value: Ipv4Addr,
+    ) -> Result<(), ConfigError<S::Error>> {
+        store_display(menu, self.name, value).await
+    }
+}
+
+impl Entry<kind::F32> {
+    pub fn get<S: NorFlash, C: BlockCipher>(
+        &self,
+        menu: &mut ConfigMenu<'_, S, C>,
+    ) -> Result<f32, ConfigError<S::Error>> {
+        block_on(self.get_async(menu))
+    }
+
+    pub async fn get_async<S: ErrorType, C: BlockCipher, M: Access<S>>(
+        &self,
+        menu: &mut ConfigMenu<'_, S, C, M>,
+    ) -> Result<f32, ConfigError<S::Error>> {
+        menu.get_f32_async(self.name).await
+    }
+
+    pub fn set<S: NorFlash, C: BlockCipher>(
+        &self,
+        menu: &mut ConfigMenu<'_, S, C>,
+        value: f32,
+    ) -> Result<(), ConfigError<S::Error>> {
+        block_on(self.set_async(menu, value))
+    }
+
+    pub async fn set_async<S: ErrorType, C: BlockCipher, M: Access<S>>(
+        &self,
+        menu: &mut ConfigMenu<'_, S, C, M>,
+        value: f32,
+    ) -> Result<(), ConfigError<S::Error>> {
+        store_display(menu, self.name, value).await
+    }
+}
+
+impl Entry<kind::Bytes> {
+    /**
+     * Reads the value into the output, and gives the length of it.
+     */
+    pub fn get<S: NorFlash, C: BlockCipher>(
+        &self,
+        menu: &mut ConfigMenu<'_, S, C>,
+        output: &mut [u8],
+    ) -> Result<usize, ConfigError<S::Error>> {
+        block_on(self.get_async(menu, output))
+    }
+
+    pub async fn get_async<S: ErrorType, C: BlockCipher, M: Access<S>>(
+        &self,
+        menu: &mut ConfigMenu<'_, S, C, M>,
+        output: &mut [u8],
+    ) -> Result<usize, ConfigError<S::Error>> {
+        menu.read_bytes_async(self.name, output).await
+    }
+
+    /**
+     * Reads the value in parts, see `ConfigMenu::read_entry_with`.
+     */
+    pub fn get_with<S: NorFlash, C: BlockCipher>(
+        &self,
+        menu: &mut ConfigMenu<'_, S, C>,
+        f: impl FnMut(&[u8]),
+    ) -> Result<usize, ConfigError<S::Error>> {
+        block_on(self.get_with_async(menu, f))
+    }
+
+    pub async fn get_with_async<S: ErrorType, C: BlockCipher, M: Access<S>>(
+        &self,
+        menu: &mut ConfigMenu<'_, S, C, M>,
+        f: impl FnMut(&[u8]),
+    ) -> Result<usize, ConfigError<S::Error>> {
+        menu.read_entry_with_async(self.name, f).await
+    }
+
+    pub fn set<S: NorFlash, C: BlockCipher>(
+        &self,
+        menu: &mut ConfigMenu<'_, S, C>,
+        value: &[u8],
+    ) -> Result<(), ConfigError<S::Error>> {
+        block_on(self.set_async(menu, value))
+    }
+
+    pub async fn set_async<S: ErrorType, C: BlockCipher, M: Access<S>>(
+        &self,
+        menu: &mut ConfigMenu<'_, S, C, M>,
+        value: &[u8],
+    ) -> Result<(), ConfigError<S::Error>> {
+        menu.store_bytes_async(self.name, value).await
+    }
+}
+
+/**
+ * Stores a value as the text it is displayed as.
+ */
+async fn store_display<S: ErrorType, C: BlockCipher, M: Access<S>>(
+    menu: &mut ConfigMenu<'_, S, C, M>,
+    name: &str,
+    value: impl Display,
+) -> Result<(), ConfigError<S::Error>> {
+    menu.store_entry_async(name, &__private::to_text(value)?)
+        .await
+}
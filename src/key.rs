@@ -1,25 +1,245 @@
+use crate::cipher::CipherKey;
+#[cfg(feature = "esp")]
+use esp_hal::efuse::Efuse;
+#[cfg(feature = "esp")]
 use esp_hal::sha::{Sha, Sha256};
 
+/** Size of the SHA-256 hash, which is the longest key `make_key` can make. */
+pub const HASH_SIZE: usize = 32;
+/** Number of PBKDF2 iterations used by the examples. */
+pub const DEFAULT_ITERATIONS: u32 = 4096;
+
+const BLOCK_SIZE: usize = 64;
+/** Salt used when deriving keys from eFuse data, which is unique for each device. */
+#[cfg(feature = "esp")]
+const EFUSE_SALT: &[u8] = b"esp-embassy-config storage key";
+
 /**
- * Makes a hash from a provided key of arbitrarty length and a salt.
- * The length of the hash is determined by the generic argument
+ * SHA-256 used for deriving keys. The esp32 SHA peripheral implements this, and with the
+ * `soft-crypto` feature a software SHA-256 is available for hosts and chips without the
+ * peripheral.
  */
-pub fn make_key<const SZ: usize>(sha: &mut Sha, key: &str) -> [u8; SZ] {
-    const SALT: &str = "SDFSMFOWRN¤#TIQN#T¤MT¤=R!E32r23r32r32fnwae";
+pub trait Sha256Hasher {
+    /**
+     * Hashes the concatenation of the parts.
+     */
+    fn hash(&mut self, parts: &[&[u8]]) -> [u8; HASH_SIZE];
+}
+
+#[cfg(feature = "esp")]
+impl Sha256Hasher for Sha<'_> {
+    fn hash(&mut self, parts: &[&[u8]]) -> [u8; HASH_SIZE] {
+        let mut hasher = self.start::<Sha256>();
+        for part in parts {
+            let mut remaining = *part;
+            while !remaining.is_empty() {
+                remaining = hasher.update(remaining).unwrap();
+            }
+        }
+
+        let mut output = [0u8; HASH_SIZE];
+        hasher.finish(output.as_mut_slice()).unwrap();
+        output
+    }
+}
 
-    let mut hasher = sha.start::<Sha256>();
-    let mut output = [0u8; SZ];
+/**
+ * Software implementation of SHA-256, from the RustCrypto `sha2` crate.
+ */
+#[cfg(feature = "soft-crypto")]
+pub struct SoftSha;
 
-    let mut remaining = key.as_bytes();
-    while remaining.len() > 0 {
-        remaining = hasher.update(remaining).unwrap();
+#[cfg(feature = "soft-crypto")]
+impl Sha256Hasher for SoftSha {
+    fn hash(&mut self, parts: &[&[u8]]) -> [u8; HASH_SIZE] {
+        use sha2::Digest;
+        let mut hasher = sha2::Sha256::new();
+        for part in parts {
+            hasher.update(part);
+        }
+        hasher.finalize().into()
     }
+}
+
+/**
+ * Salt that is unique for each device, made from the base MAC address burnt into the
+ * eFuses, so the same passphrase gives different keys on different devices.
+ */
+#[cfg(feature = "esp")]
+pub fn device_salt() -> [u8; 6] {
+    Efuse::read_base_mac_address()
+}
 
-    let mut remaining = SALT.as_bytes();
-    while remaining.len() > 0 {
-        remaining = hasher.update(remaining).unwrap();
+/**
+ * Where the storage key comes from.
+ */
+#[cfg(feature = "esp")]
+#[derive(Debug, Clone, Copy)]
+pub enum KeySource<'a> {
+    /**
+     * A passphrase, which is stretched with PBKDF2 and the device salt. Unless it is
+     * entered at runtime, the passphrase is part of the firmware image.
+     */
+    Passphrase(&'a str),
+    /**
+     * The base MAC address in the eFuses, so each device gets its own key without a
+     * secret in the firmware. The MAC address is not secret (it is sent over the air
+     * when wifi is used), so this only prevents the values from being read from a dump
+     * of the flash alone.
+     */
+    DeviceMac,
+    /**
+     * The 256 bits of a key block in the eFuses, like BLOCK_KEY5, which has been burnt
+     * with a random key. esp-hal does not give access to the key blocks, so they are
+     * read by the caller, for example from the `RD_KEY5_DATA` registers of the eFuse
+     * controller. The block must not be read protected, since it is read by software.
+     */
+    EfuseKeyBlock([u8; 32]),
+}
+
+#[cfg(feature = "esp")]
+impl KeySource<'_> {
+    /**
+     * Derives a storage key from the source. `iterations` is only used for passphrases.
+     */
+    pub fn derive<const SZ: usize>(
+        &self,
+        sha: &mut impl Sha256Hasher,
+        iterations: u32,
+    ) -> [u8; SZ] {
+        match *self {
+            KeySource::Passphrase(passphrase) => {
+                make_key(sha, passphrase, &device_salt(), iterations)
+            }
+            KeySource::DeviceMac => pbkdf2(sha, &device_salt(), EFUSE_SALT, 1),
+            KeySource::EfuseKeyBlock(key) => pbkdf2(sha, &key, EFUSE_SALT, 1),
+        }
     }
+}
+
+/**
+ * Makes keys from passphrases entered at runtime, so the key can be changed from the menu
+ * with `ConfigMenu::rekey`.
+ */
+pub trait KeyDeriver {
+    fn derive(&mut self, passphrase: &str) -> CipherKey;
+}
 
-    hasher.finish(output.as_mut_slice()).unwrap();
-    output
+/**
+ * Derives `SZ` byte keys the same way as `KeySource`, so they match the key made at boot.
+ * An empty passphrase gives the key of `KeySource::DeviceMac`.
+ */
+#[cfg(feature = "esp")]
+pub struct PassphraseDeriver<H, const SZ: usize> {
+    sha: H,
+    iterations: u32,
+}
+
+#[cfg(feature = "esp")]
+impl<H: Sha256Hasher, const SZ: usize> PassphraseDeriver<H, SZ> {
+    pub fn new(sha: H, iterations: u32) -> Self {
+        Self { sha, iterations }
+    }
+}
+
+#[cfg(feature = "esp")]
+impl<H: Sha256Hasher, const SZ: usize> KeyDeriver for PassphraseDeriver<H, SZ>
+where
+    [u8; SZ]: Into<CipherKey>,
+{
+    fn derive(&mut self, passphrase: &str) -> CipherKey {
+        let source = match passphrase {
+            "" => KeySource::DeviceMac,
+            passphrase => KeySource::Passphrase(passphrase),
+        };
+        source.derive::<SZ>(&mut self.sha, self.iterations).into()
+    }
+}
+
+/**
+ * Derives a key from a passphrase of arbitrary length and a salt, with
+ * PBKDF2-HMAC-SHA256. The length of the key is determined by the generic argument, and
+ * can not be longer than the hash.
+ */
+pub fn make_key<const SZ: usize>(
+    sha: &mut impl Sha256Hasher,
+    passphrase: &str,
+    salt: &[u8],
+    iterations: u32,
+) -> [u8; SZ] {
+    pbkdf2(sha, passphrase.as_bytes(), salt, iterations)
+}
+
+fn pbkdf2<const SZ: usize>(
+    sha: &mut impl Sha256Hasher,
+    password: &[u8],
+    salt: &[u8],
+    iterations: u32,
+) -> [u8; SZ] {
+    const {
+        assert!(
+            SZ <= HASH_SIZE,
+            "make_key can not make keys longer than the 32 byte SHA-256 hash"
+        );
+    }
+
+    let mut u = hmac(sha, password, salt, &1u32.to_be_bytes());
+    let mut output = u;
+    for _ in 1..iterations {
+        u = hmac(sha, password, &u, &[]);
+        for (out, byte) in output.iter_mut().zip(u) {
+            *out ^= byte;
+        }
+    }
+
+    let mut key = [0u8; SZ];
+    key.copy_from_slice(&output[..SZ]);
+    key
+}
+
+/**
+ * HMAC-SHA256 of the message, given in two parts.
+ */
+fn hmac(sha: &mut impl Sha256Hasher, key: &[u8], message: &[u8], more: &[u8]) -> [u8; HASH_SIZE] {
+    let mut block = [0u8; BLOCK_SIZE];
+    if key.len() > BLOCK_SIZE {
+        block[..HASH_SIZE].copy_from_slice(&sha.hash(&[key]));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+
+    let inner = sha.hash(&[&block.map(|byte| byte ^ 0x36), message, more]);
+    sha.hash(&[&block.map(|byte| byte ^ 0x5c), &inner])
+}
+
+#[cfg(all(test, feature = "soft-crypto"))]
+mod tests {
+    use super::*;
+
+    fn hex(text: &str) -> [u8; HASH_SIZE] {
+        core::array::from_fn(|i| u8::from_str_radix(&text[2 * i..2 * i + 2], 16).unwrap())
+    }
+
+    /**
+     * The PBKDF2-HMAC-SHA256 test vectors of RFC 7914 section 11, of which `make_key`
+     * gives the first 32 bytes.
+     */
+    #[test]
+    fn pbkdf2_test_vectors() {
+        assert_eq!(
+            make_key::<HASH_SIZE>(&mut SoftSha, "passwd", b"salt", 1),
+            hex("55ac046e56e3089fec1691c22544b605f94185216dde0465e68b9d57c20dacbc")
+        );
+        assert_eq!(
+            make_key::<HASH_SIZE>(&mut SoftSha, "Password", b"NaCl", 80000),
+            hex("4ddcd8f60b98be21830cee5ef22701f9641a4418d04c0414aeff08876b34ab56")
+        );
+    }
+
+    #[test]
+    fn shorter_keys_are_the_start_of_the_hash() {
+        let key = make_key::<HASH_SIZE>(&mut SoftSha, "passwd", b"salt", 1);
+        let short = make_key::<16>(&mut SoftSha, "passwd", b"salt", 1);
+        assert_eq!(short, key[..16]);
+    }
 }
@@ -1,63 +1,91 @@
-use crate::configs::ConfigMenu;
+use crate::LINE_SIZE;
+use crate::cipher::BlockCipher;
+use crate::configs::{ConfigEntry, ConfigError, ConfigMenu, ValueWriter};
+use crate::storage::{Access, Blocking};
+use crate::value::decode_bytes;
 use core::fmt;
+use embassy_executor::Spawner;
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use embassy_sync::mutex::Mutex;
-use esp_println::println;
+use embedded_storage::nor_flash::ErrorType;
+use esp_hal::aes::Aes;
+use esp_hal::{
+    Async,
+    uart::{UartRx, UartTx},
+};
+use esp_println::{print, println};
+use esp_storage::FlashStorage;
 #[cfg(feature = "wifi")]
 use esp_wifi::wifi::ClientConfiguration;
 use log::info;
 
-pub enum MenuState {
-    Idle(&'static Mutex<CriticalSectionRawMutex, ConfigMenu<'static>>),
-    Menu(&'static Mutex<CriticalSectionRawMutex, ConfigMenu<'static>>),
-    SelectChange(&'static Mutex<CriticalSectionRawMutex, ConfigMenu<'static>>),
-    NewValue(
-        &'static Mutex<CriticalSectionRawMutex, ConfigMenu<'static>>,
-        heapless::String<32>,
-    ),
-    ConfirmingReset(&'static Mutex<CriticalSectionRawMutex, ConfigMenu<'static>>),
+/**
+ * Menu shared between the menu task and the application. The defaults are the ones of
+ * `ConfigMenu`, and `M` is `storage::Async` for a menu made with `ConfigMenu::new_async`.
+ */
+pub type SharedMenu<S = FlashStorage, C = Aes<'static>, M = Blocking> =
+    &'static Mutex<CriticalSectionRawMutex, ConfigMenu<'static, S, C, M>>;
+
+pub enum MenuState<S: 'static, C: 'static, M: 'static> {
+    Idle(SharedMenu<S, C, M>),
+    Menu(SharedMenu<S, C, M>),
+    SelectChange(SharedMenu<S, C, M>),
+    NewValue(SharedMenu<S, C, M>, heapless::String<LINE_SIZE>),
+    ConfirmingReset(SharedMenu<S, C, M>),
+    SelectRestore(SharedMenu<S, C, M>),
+    OldKey(SharedMenu<S, C, M>),
+    NewKey(SharedMenu<S, C, M>, heapless::String<LINE_SIZE>),
+    SelectPaste(SharedMenu<S, C, M>),
+    Paste(SharedMenu<S, C, M>, ValueWriter<'static>),
+    /** Skips the rest of a paste that failed, until the end line. */
+    SkipPaste(SharedMenu<S, C, M>),
 }
 
-async fn list_entries(menu: &'static Mutex<CriticalSectionRawMutex, ConfigMenu<'static>>) {
+/** Line ending a pasted value. */
+const PASTE_END: &str = ".";
+
+async fn print_entry<S: ErrorType, C: BlockCipher, M: Access<S>>(
+    menu: &mut ConfigMenu<'static, S, C, M>,
+    cnt: i32,
+    entry: &ConfigEntry<'static>,
+) {
+    // the value is read twice, for its length and then to print it, so it does not
+    // have to fit in memory
+    let len = match menu.read_entry_with_async(entry.name, |_| {}).await {
+        Ok(len) => len,
+        Err(err) => {
+            println!("{}: -{}-", entry.name, err);
+            return;
+        }
+    };
+    entry.print_header(cnt, len);
+    if entry.secret {
+        println!("********");
+        return;
+    }
+    if let Err(err) = menu
+        .read_entry_with_async(entry.name, |part| entry.print_part(part))
+        .await
+    {
+        print!(" -{}-", err);
+    }
+    println!("");
+}
+
+async fn list_entries<S: ErrorType, C: BlockCipher, M: Access<S>>(menu: SharedMenu<S, C, M>) {
     println!("---------------------------");
     println!("List entries:");
     let mut unlocked = menu.lock().await;
-    let mut cnt = 0;
-    for entry in unlocked.entries.iter() {
-        let mut output = heapless::String::<32>::new();
-        let v = unlocked.read_entry(entry.name, &mut output);
-        if v.is_err() {
-            println!("{}: -read failure-", entry.name);
-        } else {
-            entry.print(cnt, output.as_str());
-        }
-        cnt += 1;
+    for (cnt, entry) in unlocked.all_entries().enumerate() {
+        print_entry(&mut unlocked, cnt as i32, entry).await;
     }
-    #[cfg(feature = "wifi")]
-    {
-        let mut output = heapless::String::<32>::new();
-        let name = unlocked.wifi_ssid.name;
-        let v = unlocked.read_entry(name, &mut output);
-        if v.is_err() {
-            println!("wifi_ssid: -read failure- name: {}", name);
-        } else {
-            unlocked.wifi_ssid.print(cnt, output.as_str());
-        }
-        let name = unlocked.wifi_pass.name;
-        let v = unlocked.read_entry(name, &mut output);
-        if v.is_err() {
-            println!("wifi_pass: -read failure-");
-        } else {
-            unlocked.wifi_pass.print(cnt + 1, output.as_str());
-        }
-        let name = unlocked.wifi_autostart.name;
-        let v = unlocked.read_entry(name, &mut output);
-        if v.is_err() {
-            println!("wifi_autostart: -read failure-");
-        } else {
-            unlocked.wifi_autostart.print(cnt + 1, output.as_str());
+    print!("Sector erase counts:");
+    for sector in 0..unlocked.sector_count() {
+        if let Ok(count) = unlocked.erase_count_async(sector).await {
+            print!(" {}", count);
         }
     }
+    println!("");
     println!("---------------------------");
     println!("");
 }
@@ -68,16 +96,19 @@ fn print_menu() {
     println!("1: show menu");
     println!("2: list entries");
     println!("3: update value");
-    println!("4: reset flash storage (useful if changing key)");
+    println!("4: reset flash storage");
     #[cfg(feature = "wifi")]
     println!("5: Connect to wifi");
+    println!("6: change key");
+    println!("7: restore defaults");
+    println!("8: paste value (multi-line)");
     println!("other: exit menu");
     println!("---------------------------");
     println!("");
 }
 
-impl MenuState {
-    pub async fn got_line(&self, line: &str) -> Self {
+impl<S: ErrorType, C: BlockCipher, M: Access<S>> MenuState<S, C, M> {
+    pub async fn got_line(self, line: &str) -> Self {
         match self {
             MenuState::Idle(menu) => {
                 if line.len() >= 1 && line.starts_with("m") {
@@ -107,14 +138,18 @@ impl MenuState {
                     let mut client_config = ClientConfiguration::default();
 
                     let mut unlocked = menu.lock().await;
-                    let res = unlocked.read_entry("wifi_ssid", &mut client_config.ssid);
-                    if res.is_err() {
-                        println!("Failed to connect to wifi, no SSID set");
+                    let res = unlocked
+                        .read_entry_async("wifi_ssid", &mut client_config.ssid)
+                        .await;
+                    if let Err(err) = res {
+                        println!("Failed to connect to wifi, SSID {}", err);
                         return MenuState::Menu(menu);
                     }
-                    let res = unlocked.read_entry("wifi_pass", &mut client_config.password);
-                    if res.is_err() {
-                        println!("Failed to connect to wifi, no Pass set");
+                    let res = unlocked
+                        .read_entry_async("wifi_pass", &mut client_config.password)
+                        .await;
+                    if let Err(err) = res {
+                        println!("Failed to connect to wifi, Pass {}", err);
                         return MenuState::Menu(menu);
                     }
 
@@ -122,36 +157,179 @@ impl MenuState {
 
                     return MenuState::Menu(menu);
                 }
+                "6" => {
+                    if !menu.lock().await.can_rekey() {
+                        println!(
+                            "Changing key needs a key deriver, see ConfigMenu::with_key_deriver"
+                        );
+                        return MenuState::Menu(menu);
+                    }
+                    return MenuState::OldKey(menu);
+                }
+                "7" => {
+                    return MenuState::SelectRestore(menu);
+                }
+                "8" => {
+                    return MenuState::SelectPaste(menu);
+                }
                 _ => return MenuState::Idle(menu),
             },
             MenuState::SelectChange(menu) => {
-                let mut name = heapless::String::<32>::new();
-                if let Ok(index) = line.parse::<usize>() {
-                    let unlocked = menu.lock().await;
-                    let entry = unlocked.get_entry_index(index);
-                    if entry.is_ok() {
-                        let _ = name.push_str(entry.unwrap().name);
-                        return MenuState::NewValue(menu, name);
-                    }
-                }
-                let _ = name.push_str(line);
+                let unlocked = menu.lock().await;
+                let name = match line.parse::<usize>() {
+                    Ok(index) => unlocked
+                        .get_entry_index(index)
+                        .map_or(line, |entry| entry.name),
+                    Err(_) => line,
+                };
+                let Ok(name) = heapless::String::try_from(name) else {
+                    println!("Failed to change {}: name is too long", name);
+                    return MenuState::Menu(menu);
+                };
                 return MenuState::NewValue(menu, name);
             }
             MenuState::NewValue(menu, value) => {
                 let mut unlocked = menu.lock().await;
-                let _ = unlocked.store_entry(value, line);
+                let binary = unlocked
+                    .get_entry(&value)
+                    .is_ok_and(|entry| entry.is_binary());
+                let res = if binary {
+                    let mut bytes = [0u8; LINE_SIZE];
+                    match decode_bytes(line, &mut bytes) {
+                        Ok(len) => unlocked.store_bytes_async(&value, &bytes[..len]).await,
+                        Err(reason) => Err(ConfigError::Invalid(reason)),
+                    }
+                } else {
+                    unlocked.store_entry_async(&value, line).await
+                };
+                if let Err(err) = res {
+                    println!("Failed to store {}: {}", value, err);
+                }
                 return MenuState::Menu(menu);
             }
             MenuState::ConfirmingReset(menu) => {
                 if line.starts_with("y") {
                     info!("Reset flash storage");
                     let mut unlocked = menu.lock().await;
-                    for entry in unlocked.entries.iter() {
-                        let _ = unlocked.store_entry(entry.name, "");
+                    if let Err(err) = unlocked.reset_async().await {
+                        println!("Failed to reset flash storage: {}", err);
                     }
                 }
                 return MenuState::Menu(menu);
             }
+            MenuState::SelectRestore(menu) => {
+                let mut unlocked = menu.lock().await;
+                let res = if line == "all" {
+                    unlocked.restore_defaults_async().await
+                } else if let Ok(index) = line.parse::<usize>() {
+                    match unlocked.get_entry_index(index) {
+                        Ok(entry) => {
+                            let name = entry.name;
+                            unlocked.restore_default_async(name).await
+                        }
+                        Err(err) => Err(err),
+                    }
+                } else {
+                    unlocked.restore_default_async(line).await
+                };
+                if let Err(err) = res {
+                    println!("Failed to restore default of {}: {}", line, err);
+                }
+                return MenuState::Menu(menu);
+            }
+            MenuState::OldKey(menu) => {
+                let Ok(old) = heapless::String::try_from(line) else {
+                    println!("Failed to change key, the passphrase is too long");
+                    return MenuState::Menu(menu);
+                };
+                return MenuState::NewKey(menu, old);
+            }
+            MenuState::NewKey(menu, old) => {
+                info!("Change key");
+                let mut unlocked = menu.lock().await;
+                match unlocked.rekey_passphrase_async(&old, line).await {
+                    Ok(_) => println!("Key changed"),
+                    Err(err) => println!("Failed to change key, {}", err),
+                }
+                return MenuState::Menu(menu);
+            }
+            MenuState::SelectPaste(menu) => {
+                let mut unlocked = menu.lock().await;
+                let name = match line.parse::<usize>() {
+                    Ok(index) => unlocked
+                        .get_entry_index(index)
+                        .map_or(line, |entry| entry.name),
+                    Err(_) => line,
+                };
+                match unlocked.begin_value_async(name).await {
+                    Ok(writer) => {
+                        println!(
+                            "Paste value of {}, and end with a line with only '{}':",
+                            writer.name(),
+                            PASTE_END
+                        );
+                        return MenuState::Paste(menu, writer);
+                    }
+                    Err(err) => {
+                        println!("Failed to paste {}: {}", name, err);
+                        return MenuState::Menu(menu);
+                    }
+                }
+            }
+            MenuState::Paste(menu, mut writer) => {
+                let mut unlocked = menu.lock().await;
+                if line == PASTE_END {
+                    let name = writer.name();
+                    match unlocked.finish_value_async(writer).await {
+                        Ok(_) => println!("Stored {}", name),
+                        Err(err) => println!("Failed to store {}: {}", name, err),
+                    }
+                    return MenuState::Menu(menu);
+                }
+                let binary = unlocked
+                    .get_entry(writer.name())
+                    .is_ok_and(|entry| entry.is_binary());
+                let res = if binary {
+                    let mut bytes = [0u8; LINE_SIZE];
+                    match decode_bytes(line, &mut bytes) {
+                        Ok(len) => unlocked.write_value_async(&mut writer, &bytes[..len]).await,
+                        Err(reason) => Err(ConfigError::Invalid(reason)),
+                    }
+                } else {
+                    match unlocked
+                        .write_value_async(&mut writer, line.as_bytes())
+                        .await
+                    {
+                        Ok(_) => unlocked.write_value_async(&mut writer, b"\n").await,
+                        Err(err) => Err(err),
+                    }
+                };
+                if let Err(err) = res {
+                    println!("Failed to store {}: {}", writer.name(), err);
+                    return MenuState::SkipPaste(menu);
+                }
+                return MenuState::Paste(menu, writer);
+            }
+            MenuState::SkipPaste(menu) => {
+                if line == PASTE_END {
+                    return MenuState::Menu(menu);
+                }
+                return MenuState::SkipPaste(menu);
+            }
+        }
+    }
+
+    /**
+     * Handles a line that was too long to be read. A paste can not go on without the
+     * line, so the rest of it is skipped, and the other states ask for the line again.
+     */
+    pub fn line_too_long(self) -> Self {
+        match self {
+            MenuState::Paste(menu, writer) => {
+                println!("Failed to store {}, skipping the rest", writer.name());
+                MenuState::SkipPaste(menu)
+            }
+            state => state,
         }
     }
 
@@ -168,7 +346,10 @@ impl MenuState {
                 let unlocked = menu.lock().await;
                 match unlocked.get_entry(name) {
                     Ok(entry) => {
-                        println!("Update entry {}: {}", entry.name, entry.question);
+                        println!(
+                            "Update entry {} ({}): {}",
+                            entry.name, entry.kind, entry.question
+                        );
                     }
                     Err(_) => {
                         println!("Entry not found: {}", name);
@@ -178,21 +359,44 @@ impl MenuState {
             MenuState::ConfirmingReset(_) => {
                 println!("Confirm flash reset with 'y':");
             }
+            MenuState::SelectRestore(_) => {
+                println!("Select entry name, or 'all':");
+            }
+            MenuState::OldKey(_) => {
+                println!("Enter current passphrase (empty for device key):");
+            }
+            MenuState::NewKey(_, _) => {
+                println!("Enter new passphrase (empty for device key):");
+            }
+            MenuState::SelectPaste(_) => {
+                println!("Select entry name:");
+            }
+            MenuState::Paste(_, _) => {}
+            MenuState::SkipPaste(_) => {}
         }
     }
 
     pub async fn secret_echo(&self) -> bool {
+        if let MenuState::OldKey(_) | MenuState::NewKey(_, _) = self {
+            return true;
+        }
         if let MenuState::NewValue(menu, name) = self {
             let unlocked = menu.lock().await;
             if let Ok(entry) = unlocked.get_entry(name) {
                 return entry.secret;
             }
         }
+        if let MenuState::Paste(menu, writer) = self {
+            let unlocked = menu.lock().await;
+            if let Ok(entry) = unlocked.get_entry(writer.name()) {
+                return entry.secret;
+            }
+        }
         false
     }
 }
 
-impl fmt::Debug for MenuState {
+impl<S, C, M> fmt::Debug for MenuState<S, C, M> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             MenuState::Idle(_) => f.debug_struct("State::Idle").finish(),
@@ -203,6 +407,138 @@ impl fmt::Debug for MenuState {
                 .field("entry", entry)
                 .finish(),
             MenuState::ConfirmingReset(_) => f.debug_struct("State::ConfirmingReset").finish(),
+            MenuState::SelectRestore(_) => f.debug_struct("State::SelectRestore").finish(),
+            MenuState::OldKey(_) => f.debug_struct("State::OldKey").finish(),
+            MenuState::NewKey(_, _) => f.debug_struct("State::NewKey").finish(),
+            MenuState::SelectPaste(_) => f.debug_struct("State::SelectPaste").finish(),
+            MenuState::Paste(_, writer) => f
+                .debug_struct("State::Paste")
+                .field("entry", &writer.name())
+                .field("written", &writer.written())
+                .finish(),
+            MenuState::SkipPaste(_) => f.debug_struct("State::SkipPaste").finish(),
+        }
+    }
+}
+
+/**
+ * Spawns the menu task for a menu on the internal flash, made with `ConfigMenu::new`.
+ * Menus on other storage, or made with `ConfigMenu::new_async`, are run with
+ * `run_config_menu`.
+ */
+pub async fn config_init(
+    spawner: Spawner,
+    config_menu: SharedMenu,
+    rx: UartRx<'static, Async>,
+    tx: UartTx<'static, Async>,
+) {
+    spawner.spawn(config_menu_task(config_menu, rx, tx)).ok();
+}
+
+#[embassy_executor::task]
+async fn config_menu_task(
+    config_menu: SharedMenu,
+    rx: UartRx<'static, Async>,
+    tx: UartTx<'static, Async>,
+) {
+    run_config_menu(config_menu, rx, tx).await
+}
+
+/**
+ * Runs the menu on the uart, for a menu on any storage, with any cipher, and blocking or
+ * async access. The values are read and stored with the `_async` methods, so with async
+ * storage the other tasks run while the menu writes to it. Embassy tasks can not be
+ * generic, so this is awaited in a task of the application, like:
+ *
+ * ```ignore
+ * type Menu = SharedMenu<SpiFlash, Aes<'static>, storage::Async>;
+ *
+ * #[embassy_executor::task]
+ * async fn menu_task(menu: Menu, rx: UartRx<'static, Async>, tx: UartTx<'static, Async>) {
+ *     run_config_menu(menu, rx, tx).await
+ * }
+ * ```
+ */
+pub async fn run_config_menu<S: ErrorType, C: BlockCipher, M: Access<S>>(
+    config_menu: SharedMenu<S, C, M>,
+    mut rx: UartRx<'static, Async>,
+    mut tx: UartTx<'static, Async>,
+) -> ! {
+    #[cfg(feature = "wifi")]
+    {
+        let mut c = config_menu.lock().await;
+        if let Err(err) = c.autostart_wifi().await {
+            println!("Failed to start wifi: {}", err);
+        }
+    }
+
+    let mut state = MenuState::Idle(config_menu);
+    loop {
+        let secret_echo = state.secret_echo().await;
+        match get_line::<LINE_SIZE>(&mut rx, &mut tx, secret_echo).await {
+            Ok(line) => state = state.got_line(line.as_str()).await,
+            Err(LineError::TooLong) => {
+                println!("Line is longer than {} characters, ignored", LINE_SIZE);
+                state = state.line_too_long();
+            }
+            Err(LineError::Uart) => continue,
+        }
+        state.run_state().await;
+    }
+}
+
+/** Why `get_line` gave no line. */
+enum LineError {
+    /** The line was longer than the buffer. It has been read to its end, and dropped. */
+    TooLong,
+    Uart,
+}
+
+async fn get_line<const SZ: usize>(
+    rx: &mut UartRx<'static, Async>,
+    tx: &mut UartTx<'static, Async>,
+    secret_echo: bool,
+) -> Result<heapless::String<SZ>, LineError> {
+    let mut buf: [u8; 1] = [0; 1];
+    let mut line = heapless::String::<SZ>::new();
+    let mut too_long = false;
+    loop {
+        let res = rx.read_async(buf.as_mut_slice()).await;
+        match res {
+            Ok(len) => {
+                if len < 1 {
+                    continue;
+                }
+
+                // pasted text can end lines with LF, or CR LF, where the LF comes
+                // after the line was already ended by the CR
+                if buf[0] == 10 && line.is_empty() {
+                    continue;
+                }
+                if buf[0] == 13 || buf[0] == 10 {
+                    let _ = tx.write_async(&[13]).await;
+                    let _ = tx.flush_async().await;
+                    return if too_long {
+                        Err(LineError::TooLong)
+                    } else {
+                        Ok(line)
+                    };
+                }
+
+                if secret_echo {
+                    let _ = tx.write_async("*".as_bytes()).await;
+                } else {
+                    let _ = tx.write_async(&buf).await;
+                }
+                let _ = tx.flush_async().await;
+
+                // the rest of a line that is too long is read, so it is not taken as
+                // the next line
+                if line.push(buf[0] as char).is_err() {
+                    too_long = true;
+                }
+            }
+            Err(_) => return Err(LineError::Uart),
         }
     }
 }
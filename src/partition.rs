@@ -0,0 +1,334 @@
+use crate::storage::StorageRegion;
+use embedded_storage::ReadStorage;
+
+/** Offset of the partition table in flash. */
+pub const PARTITION_TABLE_OFFSET: u32 = 0x8000;
+/** Maximum length of the partition table, including the md5 entry. */
+pub const PARTITION_TABLE_MAX_LEN: usize = 0xc00;
+/** Size of each entry in the partition table. */
+pub const ENTRY_SIZE: usize = 32;
+
+pub const TYPE_APP: u8 = 0x00;
+pub const TYPE_DATA: u8 = 0x01;
+
+pub const SUBTYPE_DATA_OTA: u8 = 0x00;
+pub const SUBTYPE_DATA_PHY: u8 = 0x01;
+pub const SUBTYPE_DATA_NVS: u8 = 0x02;
+pub const SUBTYPE_DATA_COREDUMP: u8 = 0x03;
+pub const SUBTYPE_DATA_NVS_KEYS: u8 = 0x04;
+pub const SUBTYPE_DATA_EFUSE: u8 = 0x05;
+pub const SUBTYPE_DATA_UNDEFINED: u8 = 0x06;
+
+const MAGIC: [u8; 2] = [0xaa, 0x50];
+const MD5_MAGIC: [u8; 2] = [0xeb, 0xeb];
+const END_MAGIC: [u8; 2] = [0xff, 0xff];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionError<E> {
+    /** Reading the partition table from storage failed. */
+    Storage(E),
+    /** An entry in the table has an unknown magic. */
+    InvalidTable,
+    /** No partition matched. */
+    NotFound,
+    /** The partition was found, but is smaller than needed. */
+    TooSmall { size: u32, needed: u32 },
+}
+
+/**
+ * An entry in the esp partition table.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Partition {
+    pub ptype: u8,
+    pub subtype: u8,
+    pub offset: u32,
+    pub size: u32,
+    pub label: [u8; 16],
+    pub flags: u32,
+}
+
+enum TableEntry {
+    Partition(Partition),
+    Md5,
+    End,
+}
+
+impl Partition {
+    fn parse(entry: &[u8; ENTRY_SIZE]) -> Result<TableEntry, ()> {
+        match [entry[0], entry[1]] {
+            MAGIC => {}
+            MD5_MAGIC => return Ok(TableEntry::Md5),
+            END_MAGIC => return Ok(TableEntry::End),
+            _ => return Err(()),
+        }
+
+        let word =
+            |i: usize| u32::from_le_bytes([entry[i], entry[i + 1], entry[i + 2], entry[i + 3]]);
+        let mut label = [0u8; 16];
+        label.copy_from_slice(&entry[12..28]);
+        Ok(TableEntry::Partition(Self {
+            ptype: entry[2],
+            subtype: entry[3],
+            offset: word(4),
+            size: word(8),
+            label,
+            flags: word(28),
+        }))
+    }
+
+    /**
+     * The label, up to the first zero byte. Returns an empty string if the label is
+     * not valid utf8.
+     */
+    pub fn label(&self) -> &str {
+        let len = self.label.iter().position(|b| *b == 0).unwrap_or(16);
+        core::str::from_utf8(&self.label[..len]).unwrap_or("")
+    }
+
+    /**
+     * The storage region covered by the partition, to be used by `ConfigMenu`.
+     */
+    pub fn region(&self) -> StorageRegion {
+        StorageRegion::new(self.offset, self.size)
+    }
+}
+
+/**
+ * Partition table parsed from a byte buffer, for instance a copy of the table read
+ * from flash or a fixture. The md5 entry is skipped, and not verified.
+ */
+pub struct PartitionTable<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> PartitionTable<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    /**
+     * Iterates the partitions in the table. Stops at the end marker, the end of the
+     * buffer or the first invalid entry.
+     */
+    pub fn iter(&self) -> impl Iterator<Item = Partition> + 'a {
+        self.data
+            .chunks_exact(ENTRY_SIZE)
+            .map(|chunk| Partition::parse(chunk.try_into().unwrap()))
+            .take_while(|entry| matches!(entry, Ok(TableEntry::Partition(_) | TableEntry::Md5)))
+            .filter_map(|entry| match entry {
+                Ok(TableEntry::Partition(partition)) => Some(partition),
+                _ => None,
+            })
+    }
+
+    pub fn find_by_label(&self, label: &str) -> Option<Partition> {
+        self.iter().find(|p| p.label() == label)
+    }
+
+    pub fn find_by_type(&self, ptype: u8, subtype: u8) -> Option<Partition> {
+        self.iter()
+            .find(|p| p.ptype == ptype && p.subtype == subtype)
+    }
+}
+
+/**
+ * Reads the partition table from storage, one entry at a time, and returns the first
+ * partition where `matches` returns true.
+ */
+pub fn find_partition<S: ReadStorage>(
+    storage: &mut S,
+    matches: impl Fn(&Partition) -> bool,
+) -> Result<Partition, PartitionError<S::Error>> {
+    let mut offset = PARTITION_TABLE_OFFSET;
+    while offset < PARTITION_TABLE_OFFSET + PARTITION_TABLE_MAX_LEN as u32 {
+        let mut entry = [0u8; ENTRY_SIZE];
+        storage
+            .read(offset, &mut entry)
+            .map_err(PartitionError::Storage)?;
+        offset += ENTRY_SIZE as u32;
+
+        match Partition::parse(&entry) {
+            Ok(TableEntry::Partition(partition)) => {
+                if matches(&partition) {
+                    return Ok(partition);
+                }
+            }
+            Ok(TableEntry::Md5) => {}
+            Ok(TableEntry::End) => break,
+            Err(_) => return Err(PartitionError::InvalidTable),
+        }
+    }
+    Err(PartitionError::NotFound)
+}
+
+/**
+ * Finds the data partition with the given label, and returns its region if it has
+ * room for at least `needed` bytes.
+ */
+pub fn find_config_region<S: ReadStorage>(
+    storage: &mut S,
+    label: &str,
+    needed: u32,
+) -> Result<StorageRegion, PartitionError<S::Error>> {
+    let partition = find_partition(storage, |p| p.ptype == TYPE_DATA && p.label() == label)?;
+    if partition.size < needed {
+        return Err(PartitionError::TooSmall {
+            size: partition.size,
+            needed,
+        });
+    }
+    Ok(partition.region())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{MemError, MemStorage};
+
+    /** The table generated from partitions.csv, with the md5 entry and the end marker. */
+    const TABLE: [u8; 6 * ENTRY_SIZE] = [
+        // nvs
+        0xaa, 0x50, 0x01, 0x02, 0x00, 0x90, 0x00, 0x00, 0x00, 0x60, 0x00, 0x00, 0x6e, 0x76, 0x73,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, //
+        // phy_init
+        0xaa, 0x50, 0x01, 0x01, 0x00, 0xf0, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x70, 0x68, 0x79,
+        0x5f, 0x69, 0x6e, 0x69, 0x74, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, //
+        // config
+        0xaa, 0x50, 0x01, 0x06, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x00, 0x63, 0x6f, 0x6e,
+        0x66, 0x69, 0x67, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, //
+        // factory
+        0xaa, 0x50, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x1e, 0x00, 0x66, 0x61, 0x63,
+        0x74, 0x6f, 0x72, 0x79, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, //
+        // md5
+        0xeb, 0xeb, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        0xff, 0xbe, 0x4b, 0x1e, 0x8f, 0xc1, 0x28, 0x63, 0x44, 0x95, 0xf0, 0x2f, 0xf2, 0x65, 0x34,
+        0x5c, 0xf2, //
+        // end
+        0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        0xff, 0xff,
+    ];
+
+    const FLASH_LEN: usize = PARTITION_TABLE_OFFSET as usize + PARTITION_TABLE_MAX_LEN + ENTRY_SIZE;
+
+    type Flash = MemStorage<FLASH_LEN>;
+
+    fn flash_with(table: &[u8]) -> Flash {
+        let mut flash = Flash::new();
+        let offset = PARTITION_TABLE_OFFSET as usize;
+        flash.data[offset..offset + table.len()].copy_from_slice(table);
+        flash
+    }
+
+    #[test]
+    fn table_finds_partitions() {
+        let table = PartitionTable::new(&TABLE);
+        assert_eq!(table.iter().count(), 4);
+
+        let config = table.find_by_label("config").unwrap();
+        assert_eq!(config.ptype, TYPE_DATA);
+        assert_eq!(config.subtype, SUBTYPE_DATA_UNDEFINED);
+        assert_eq!(config.region(), StorageRegion::new(0x10000, 0x10000));
+
+        let phy = table.find_by_type(TYPE_DATA, SUBTYPE_DATA_PHY).unwrap();
+        assert_eq!(phy.label(), "phy_init");
+        assert_eq!(phy.region(), StorageRegion::new(0xf000, 0x1000));
+        let factory = table.find_by_type(TYPE_APP, 0x00).unwrap();
+        assert_eq!(factory.size, 0x1e0000);
+    }
+
+    #[test]
+    fn table_does_not_find_missing_partitions() {
+        let table = PartitionTable::new(&TABLE);
+        assert_eq!(table.find_by_label("missing"), None);
+        assert_eq!(table.find_by_label("conf"), None);
+        assert_eq!(table.find_by_type(TYPE_DATA, SUBTYPE_DATA_COREDUMP), None);
+        assert_eq!(table.find_by_type(TYPE_APP, SUBTYPE_DATA_UNDEFINED), None);
+    }
+
+    #[test]
+    fn table_stops_at_truncated_and_invalid_entries() {
+        let truncated = PartitionTable::new(&TABLE[..2 * ENTRY_SIZE + 10]);
+        assert_eq!(truncated.iter().count(), 2);
+        assert_eq!(truncated.find_by_label("config"), None);
+
+        let mut bad_magic = TABLE;
+        bad_magic[2 * ENTRY_SIZE] = 0x00;
+        let table = PartitionTable::new(&bad_magic);
+        assert_eq!(table.iter().count(), 2);
+        assert_eq!(table.find_by_label("factory"), None);
+    }
+
+    #[test]
+    fn finds_config_region_in_flash() {
+        let mut flash = flash_with(&TABLE);
+        assert_eq!(
+            find_config_region(&mut flash, "config", 0x10000),
+            Ok(StorageRegion::new(0x10000, 0x10000))
+        );
+        assert_eq!(
+            find_config_region(&mut flash, "config", 0x10001),
+            Err(PartitionError::TooSmall {
+                size: 0x10000,
+                needed: 0x10001
+            })
+        );
+        // only data partitions are used for config
+        assert_eq!(
+            find_config_region(&mut flash, "factory", 0x1000),
+            Err(PartitionError::NotFound)
+        );
+        assert_eq!(
+            find_config_region(&mut flash, "missing", 0x1000),
+            Err(PartitionError::NotFound)
+        );
+    }
+
+    #[test]
+    fn reports_invalid_table_in_flash() {
+        let mut bad_magic = TABLE;
+        bad_magic[2 * ENTRY_SIZE + 1] = 0x51;
+        let mut flash = flash_with(&bad_magic);
+        assert_eq!(
+            find_config_region(&mut flash, "config", 0x1000),
+            Err(PartitionError::InvalidTable)
+        );
+        // the entries before the invalid one are still found
+        assert_eq!(
+            find_config_region(&mut flash, "nvs", 0x1000),
+            Ok(StorageRegion::new(0x9000, 0x6000))
+        );
+    }
+
+    #[test]
+    fn reports_truncated_flash() {
+        let mut flash = MemStorage::<{ PARTITION_TABLE_OFFSET as usize + 2 * ENTRY_SIZE }>::new();
+        let offset = PARTITION_TABLE_OFFSET as usize;
+        flash.data[offset..].copy_from_slice(&TABLE[..2 * ENTRY_SIZE]);
+        assert_eq!(
+            find_config_region(&mut flash, "config", 0x1000),
+            Err(PartitionError::Storage(MemError::OutOfBounds))
+        );
+    }
+
+    #[test]
+    fn does_not_read_past_the_table() {
+        // a full table without end marker, and a partition just after it
+        let mut flash = Flash::new();
+        let offset = PARTITION_TABLE_OFFSET as usize;
+        for entry in flash.data[offset..].chunks_exact_mut(ENTRY_SIZE) {
+            entry.copy_from_slice(&TABLE[..ENTRY_SIZE]);
+        }
+        flash.data[FLASH_LEN - ENTRY_SIZE..]
+            .copy_from_slice(&TABLE[2 * ENTRY_SIZE..3 * ENTRY_SIZE]);
+        assert_eq!(
+            find_config_region(&mut flash, "config", 0x1000),
+            Err(PartitionError::NotFound)
+        );
+    }
+}
@@ -0,0 +1,1109 @@
+use crate::storage::{Access, Blocking, StorageRegion};
+use core::iter;
+use core::marker::PhantomData;
+use embedded_storage::nor_flash::ErrorType;
+
+/*
+ * Log structured storage of records, spread over the sectors of a region.
+ *
+ * Every sector starts with a 16 byte header:
+ *   0..4   erase count, written right after the sector is erased
+ *   4..8   generation, higher than that of every other sector when it is written,
+ *          which is right after the sector is erased, or when a sector that was
+ *          never erased by the store is taken into use
+ *   8..12  magic, written when the sector is taken into use
+ *   12..16 sequence number of the sector, increasing for every sector taken into use
+ *
+ * The records follow the header, each starting with a 16 byte header:
+ *   0..4   key
+ *   4..6   length of the payload
+ *   6..8   flags
+ *   8..12  transaction id
+ *   12..16 CRC32 of the payload, written after the payload
+ * and then the payload, padded to a multiple of 16 bytes.
+ *
+ * Records are written in transactions, which end with a commit record holding the
+ * transaction id. Only one transaction is written at a time, so a record is visible
+ * when the next commit record has the same transaction id as the record. Starting a
+ * transaction interrupts the one being written, which can then not be written to or
+ * committed. If power is lost in the middle of a transaction, its records are ignored,
+ * and the previous values are kept. The last committed record for a key is the
+ * current value.
+ * A key is removed by writing an empty record with the `REMOVED` flag, which hides
+ * the older records of the key.
+ *
+ * The sectors are used as a ring, and one sector is always kept erased. The space for
+ * a transaction is reserved before it is started. If it does not fit, the oldest
+ * sector is garbage collected: the erased sector is taken into use, the current
+ * records of the oldest sector are copied into it in a transaction of their own, and
+ * the oldest sector is erased. Since all sectors are only in use during garbage
+ * collection, mount finishes or rolls back an interrupted collection. The last 16
+ * bytes of a sector are only used for commit records, so the current records of a
+ * sector always fit in a new sector together with their commit record.
+ */
+
+const SECTOR_MAGIC: u32 = 0x4746_4345;
+const HEADER_SIZE: u32 = 16;
+const ERASED: u32 = 0xffff_ffff;
+const COMMIT_KEY: u32 = 0xffff_fffe;
+/** Flag of a record that removes the key. */
+const REMOVED: u16 = 0x0001;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordError<E> {
+    Flash(E),
+    /** There is no room for the records, even after garbage collection. */
+    Full,
+    /** The sector headers are inconsistent, and the store should be formatted. */
+    Corrupt,
+    /** The record is larger than what fits in a sector, or than what was reserved. */
+    TooLarge,
+    /** Another transaction was started before the transaction was committed. */
+    Interrupted,
+}
+
+/**
+ * A record found in the store.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Record {
+    pub key: u32,
+    pub len: u16,
+    pub flags: u16,
+    txn: u32,
+    crc: u32,
+    addr: u32,
+}
+
+/**
+ * A transaction that is being written, started by `RecordStore::begin_txn`. The
+ * records are visible once it is passed to `RecordStore::commit`.
+ */
+pub struct Transaction {
+    id: u32,
+    remaining: u32,
+}
+
+/**
+ * A record that is being written. Payload written through `RecordStore::write` is
+ * buffered in 16 byte blocks, and the last block is written by `RecordStore::finish`.
+ */
+pub struct RecordWriter {
+    txn: u32,
+    header: u32,
+    addr: u32,
+    end: u32,
+    block: [u8; 16],
+    fill: usize,
+    crc: u32,
+    stamp: [u8; 12],
+}
+
+impl RecordWriter {
+    /**
+     * Value that is unique for every record written to the store, made from the
+     * generation of the sector, the address of the record and the transaction id. An
+     * address is only used once until the sector is erased, which gives it a new
+     * generation, so it can be used as a nonce.
+     */
+    pub fn stamp(&self) -> [u8; 12] {
+        self.stamp
+    }
+}
+
+enum SectorState {
+    Erased,
+    Open(u32),
+    Invalid,
+}
+
+/**
+ * Records stored in a region of the flash. `M` tells how the flash is accessed, see
+ * `storage::Access`. With `Blocking` the futures are ready at once, so they can be run
+ * with `embassy_futures::block_on`.
+ */
+pub struct RecordStore<S, M = Blocking> {
+    flash: S,
+    access: PhantomData<M>,
+    region: StorageRegion,
+    sectors: u32,
+    head: u32,
+    head_seq: u32,
+    used: u32,
+    write_pos: u32,
+    last_txn: u32,
+}
+
+/**
+ * Space used by a record with the given payload length, including the header.
+ */
+pub const fn record_size(len: usize) -> u32 {
+    HEADER_SIZE + len.next_multiple_of(16) as u32
+}
+
+fn newer(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) > 0
+}
+
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/**
+ * Updates a CRC32 (as used by ethernet and zip), which starts at `!0` and is inverted
+ * when done.
+ */
+fn crc32_update(mut crc: u32, bytes: &[u8]) -> u32 {
+    for byte in bytes {
+        crc = CRC_TABLE[((crc ^ *byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+
+/**
+ * Largest record in sectors of the given size, leaving room for the sector header and a
+ * commit record.
+ */
+pub const fn max_record_size(sector_size: u32) -> u32 {
+    sector_size - 2 * HEADER_SIZE
+}
+
+impl<S: ErrorType, M: Access<S>> RecordStore<S, M> {
+    const SECTOR_SIZE: u32 = M::ERASE_SIZE as u32;
+
+    /** Largest record, see `max_record_size`. */
+    pub const MAX_RECORD_SIZE: u32 = max_record_size(Self::SECTOR_SIZE);
+
+    /**
+     * Creates the store in the region, which has to be aligned to the erase size and
+     * contain at least two sectors. `mount` has to be called before it is used.
+     */
+    pub fn new(flash: S, region: StorageRegion) -> Result<Self, RecordError<S::Error>> {
+        const {
+            assert!(4 % M::WRITE_SIZE == 0);
+            assert!(M::ERASE_SIZE % 16 == 0);
+        }
+        let sectors = region.len / Self::SECTOR_SIZE;
+        if sectors < 2 {
+            return Err(RecordError::Full);
+        }
+        Ok(Self {
+            flash,
+            access: PhantomData,
+            region,
+            sectors,
+            head: 0,
+            head_seq: 0,
+            used: 0,
+            write_pos: HEADER_SIZE,
+            last_txn: 0,
+        })
+    }
+
+    /**
+     * Finds the sectors in use and where the next record is written. Sectors with a
+     * broken header are erased, and an interrupted garbage collection is finished. If
+     * the sectors in use are inconsistent, `RecordError::Corrupt` is returned, and the
+     * store has to be formatted.
+     */
+    pub async fn mount(&mut self) -> Result<(), RecordError<S::Error>> {
+        self.used = 0;
+        for sector in 0..self.sectors {
+            match self.sector_state(sector).await? {
+                SectorState::Erased => {}
+                SectorState::Open(seq) => {
+                    if self.used == 0 || newer(seq, self.head_seq) {
+                        self.head = sector;
+                        self.head_seq = seq;
+                    }
+                    self.used += 1;
+                }
+                SectorState::Invalid => self.erase_sector(sector).await?,
+            }
+        }
+
+        // the sectors in use have to follow each other in the ring
+        for k in 0..self.used {
+            let sector = (self.head + self.sectors - k) % self.sectors;
+            match self.sector_state(sector).await? {
+                SectorState::Open(seq) if seq == self.head_seq.wrapping_sub(k) => {}
+                _ => {
+                    self.used = 0;
+                    return Err(RecordError::Corrupt);
+                }
+            }
+        }
+
+        if self.used == self.sectors {
+            self.finish_collect().await?;
+        }
+
+        let mut last_txn = None;
+        self.scan(|record| {
+            if last_txn.is_none_or(|last| newer(record.txn, last)) {
+                last_txn = Some(record.txn);
+            }
+        })
+        .await?;
+        self.last_txn = last_txn.unwrap_or(0);
+
+        let mut pos = HEADER_SIZE;
+        if self.used > 0 {
+            while let Some(record) = self.slot(self.head, pos).await? {
+                pos += record_size(record.len as usize);
+            }
+            // a record that was cut short is left alone by not writing more to the sector
+            if !self.is_blank(self.head, pos).await? {
+                pos = Self::SECTOR_SIZE;
+            }
+        }
+        self.write_pos = pos;
+        Ok(())
+    }
+
+    /**
+     * Erases all sectors, removing every record.
+     */
+    pub async fn format(&mut self) -> Result<(), RecordError<S::Error>> {
+        // the sector with the highest generation is erased last, so if the power is
+        // cut before its generation is written again, a higher one is left elsewhere
+        let mut newest = 0;
+        let mut highest = 0;
+        for sector in 0..self.sectors {
+            let generation = self.generation(sector).await?;
+            if generation != ERASED && generation >= highest {
+                newest = sector;
+                highest = generation;
+            }
+        }
+        for k in 1..=self.sectors {
+            self.erase_sector((newest + k) % self.sectors).await?;
+        }
+        self.used = 0;
+        self.write_pos = HEADER_SIZE;
+        Ok(())
+    }
+
+    pub fn sector_count(&self) -> u32 {
+        self.sectors
+    }
+
+    /**
+     * Number of times the sector has been erased by the store.
+     */
+    pub async fn erase_count(&mut self, sector: u32) -> Result<u32, RecordError<S::Error>> {
+        let count = self.read_u32(self.sector_addr(sector)).await?;
+        Ok(if count == ERASED { 0 } else { count })
+    }
+
+    /**
+     * Total size of records that can be stored, when no record is larger than
+     * `max_record` (as given by `record_size`). Space is reserved for one sector that
+     * is always erased, for the commit record at the end of each sector, and for the
+     * end of each sector that may be too small for the next record.
+     */
+    pub fn capacity(&self, max_record: u32) -> u32 {
+        (self.sectors - 1) * Self::MAX_RECORD_SIZE.saturating_sub(max_record)
+    }
+
+    /**
+     * Finds the last committed record written with the key.
+     */
+    pub async fn find(&mut self, key: u32) -> Result<Option<Record>, RecordError<S::Error>> {
+        let mut found = None;
+        let mut pending = None;
+        let mut pending_txn = None;
+        self.scan(|record| {
+            if record.key == COMMIT_KEY {
+                if pending_txn == Some(record.txn) && pending.is_some() {
+                    found = pending.take();
+                }
+            } else {
+                if pending_txn != Some(record.txn) {
+                    pending = None;
+                    pending_txn = Some(record.txn);
+                }
+                if record.key == key {
+                    pending = Some(*record);
+                }
+            }
+        })
+        .await?;
+        Ok(found.filter(|record| record.flags & REMOVED == 0))
+    }
+
+    /**
+     * Calls `f` with the key of every record in the store, including old and removed
+     * ones, so a key can be given more than once.
+     */
+    pub async fn for_each_key(
+        &mut self,
+        mut f: impl FnMut(u32),
+    ) -> Result<(), RecordError<S::Error>> {
+        self.scan(|record| {
+            if record.key != COMMIT_KEY {
+                f(record.key)
+            }
+        })
+        .await
+    }
+
+    /**
+     * Reads payload of the record, starting at `offset`. The payload is padded with
+     * 0xff to a multiple of 16 bytes, so reading whole 16 byte blocks is allowed.
+     */
+    pub async fn read(
+        &mut self,
+        record: &Record,
+        offset: u32,
+        bytes: &mut [u8],
+    ) -> Result<(), RecordError<S::Error>> {
+        if offset as usize + bytes.len() > (record.len as usize).next_multiple_of(16) {
+            return Err(RecordError::TooLarge);
+        }
+        M::read(&mut self.flash, record.addr + offset, bytes)
+            .await
+            .map_err(RecordError::Flash)
+    }
+
+    /**
+     * Checks the payload of the record against the CRC in the header, to detect bit
+     * flips and records that were not written completely.
+     */
+    pub async fn verify(&mut self, record: &Record) -> Result<bool, RecordError<S::Error>> {
+        let mut crc = !0;
+        let mut block = [0u8; 16];
+        for pos in (0..record.len as usize).step_by(16) {
+            let n = (record.len as usize - pos).min(16);
+            M::read(&mut self.flash, record.addr + pos as u32, &mut block)
+                .await
+                .map_err(RecordError::Flash)?;
+            crc = crc32_update(crc, &block[..n]);
+        }
+        Ok(!crc == record.crc)
+    }
+
+    /**
+     * Starts a transaction for records with the given payload lengths, garbage
+     * collecting old sectors if needed to make room for them. The records have to be
+     * written in the same order as the lengths are given.
+     */
+    pub async fn begin_txn(
+        &mut self,
+        lens: impl Iterator<Item = usize> + Clone,
+    ) -> Result<Transaction, RecordError<S::Error>> {
+        let mut remaining = HEADER_SIZE;
+        for len in lens.clone() {
+            if record_size(len) > Self::MAX_RECORD_SIZE || len > u16::MAX as usize {
+                return Err(RecordError::TooLarge);
+            }
+            remaining += record_size(len);
+        }
+        self.reserve(lens).await?;
+
+        self.last_txn = self.last_txn.wrapping_add(1);
+        Ok(Transaction {
+            id: self.last_txn,
+            remaining,
+        })
+    }
+
+    /**
+     * True if the transaction can still be written to, and has not been interrupted by
+     * another transaction.
+     */
+    pub fn is_open(&self, txn: &Transaction) -> bool {
+        txn.id == self.last_txn
+    }
+
+    /**
+     * Writes the commit record of the transaction, which makes its records visible.
+     */
+    pub async fn commit(&mut self, txn: Transaction) -> Result<(), RecordError<S::Error>> {
+        if txn.id != self.last_txn {
+            return Err(RecordError::Interrupted);
+        }
+        if self.used == 0 || self.write_pos + HEADER_SIZE > Self::SECTOR_SIZE {
+            self.open_next().await?;
+        }
+        let addr = self.sector_addr(self.head) + self.write_pos;
+        self.write_pos += HEADER_SIZE;
+        self.write_record_header(addr, COMMIT_KEY, 0, 0, txn.id, ERASED)
+            .await
+    }
+
+    /**
+     * Starts a record with a payload of `len` bytes in the transaction.
+     */
+    pub async fn begin(
+        &mut self,
+        txn: &mut Transaction,
+        key: u32,
+        len: usize,
+        flags: u16,
+    ) -> Result<RecordWriter, RecordError<S::Error>> {
+        let size = record_size(len);
+        if size + HEADER_SIZE > txn.remaining || key >= COMMIT_KEY {
+            return Err(RecordError::TooLarge);
+        }
+        if txn.id != self.last_txn {
+            return Err(RecordError::Interrupted);
+        }
+        if self.used == 0 || self.write_pos + size + HEADER_SIZE > Self::SECTOR_SIZE {
+            self.open_next().await?;
+        }
+        txn.remaining -= size;
+
+        let addr = self.sector_addr(self.head) + self.write_pos;
+        let mut stamp = [0u8; 12];
+        stamp[..4].copy_from_slice(&self.generation(self.head).await?.to_le_bytes());
+        stamp[4..8].copy_from_slice(&addr.to_le_bytes());
+        stamp[8..].copy_from_slice(&txn.id.to_le_bytes());
+
+        self.write_pos += size;
+        self.write_record_header(addr, key, len as u16, flags, txn.id, ERASED)
+            .await?;
+        Ok(RecordWriter {
+            txn: txn.id,
+            header: addr,
+            addr: addr + HEADER_SIZE,
+            end: addr + HEADER_SIZE + len as u32,
+            block: [0xff; 16],
+            fill: 0,
+            crc: !0,
+            stamp,
+        })
+    }
+
+    /**
+     * Appends payload to a record started with `begin`.
+     */
+    pub async fn write(
+        &mut self,
+        writer: &mut RecordWriter,
+        mut bytes: &[u8],
+    ) -> Result<(), RecordError<S::Error>> {
+        if writer.addr + (writer.fill + bytes.len()) as u32 > writer.end {
+            return Err(RecordError::TooLarge);
+        }
+        if writer.txn != self.last_txn {
+            return Err(RecordError::Interrupted);
+        }
+        writer.crc = crc32_update(writer.crc, bytes);
+        while !bytes.is_empty() {
+            let n = (16 - writer.fill).min(bytes.len());
+            writer.block[writer.fill..writer.fill + n].copy_from_slice(&bytes[..n]);
+            writer.fill += n;
+            bytes = &bytes[n..];
+            if writer.fill == 16 {
+                M::write(&mut self.flash, writer.addr, &writer.block)
+                    .await
+                    .map_err(RecordError::Flash)?;
+                writer.addr += 16;
+                writer.block = [0xff; 16];
+                writer.fill = 0;
+            }
+        }
+        Ok(())
+    }
+
+    /**
+     * Writes the last part of the payload, and the CRC of it.
+     */
+    pub async fn finish(&mut self, writer: RecordWriter) -> Result<(), RecordError<S::Error>> {
+        if writer.txn != self.last_txn {
+            return Err(RecordError::Interrupted);
+        }
+        if writer.fill > 0 {
+            M::write(&mut self.flash, writer.addr, &writer.block)
+                .await
+                .map_err(RecordError::Flash)?;
+        }
+        M::write(
+            &mut self.flash,
+            writer.header + 12,
+            &(!writer.crc).to_le_bytes(),
+        )
+        .await
+        .map_err(RecordError::Flash)
+    }
+
+    /**
+     * Writes a whole record in a transaction of its own.
+     */
+    pub async fn append(
+        &mut self,
+        key: u32,
+        flags: u16,
+        payload: &[u8],
+    ) -> Result<(), RecordError<S::Error>> {
+        let mut txn = self.begin_txn(iter::once(payload.len())).await?;
+        let mut writer = self.begin(&mut txn, key, payload.len(), flags).await?;
+        self.write(&mut writer, payload).await?;
+        self.finish(writer).await?;
+        self.commit(txn).await
+    }
+
+    /**
+     * Removes the key in the transaction, with an empty record that takes no payload
+     * length in `begin_txn`. Removed keys are dropped by the garbage collection.
+     */
+    pub async fn remove(
+        &mut self,
+        txn: &mut Transaction,
+        key: u32,
+    ) -> Result<(), RecordError<S::Error>> {
+        let writer = self.begin(txn, key, 0, REMOVED).await?;
+        self.finish(writer).await
+    }
+
+    fn oldest(&self) -> u32 {
+        (self.head + self.sectors + 1 - self.used) % self.sectors
+    }
+
+    fn sector_addr(&self, sector: u32) -> u32 {
+        self.region.offset + sector * Self::SECTOR_SIZE
+    }
+
+    async fn read_u32(&mut self, addr: u32) -> Result<u32, RecordError<S::Error>> {
+        let mut bytes = [0u8; 4];
+        M::read(&mut self.flash, addr, &mut bytes)
+            .await
+            .map_err(RecordError::Flash)?;
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    async fn sector_state(&mut self, sector: u32) -> Result<SectorState, RecordError<S::Error>> {
+        let addr = self.sector_addr(sector);
+        let magic = self.read_u32(addr + 8).await?;
+        let seq = self.read_u32(addr + 12).await?;
+        Ok(match (magic, seq) {
+            (ERASED, ERASED) => SectorState::Erased,
+            (SECTOR_MAGIC, seq) if seq != ERASED => SectorState::Open(seq),
+            _ => SectorState::Invalid,
+        })
+    }
+
+    /**
+     * Checks that the sector is erased from `pos` to the end.
+     */
+    async fn is_blank(&mut self, sector: u32, pos: u32) -> Result<bool, RecordError<S::Error>> {
+        let addr = self.sector_addr(sector);
+        let mut block = [0u8; 16];
+        for pos in (pos..Self::SECTOR_SIZE).step_by(16) {
+            M::read(&mut self.flash, addr + pos, &mut block)
+                .await
+                .map_err(RecordError::Flash)?;
+            if block.iter().any(|b| *b != 0xff) {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    async fn generation(&mut self, sector: u32) -> Result<u32, RecordError<S::Error>> {
+        self.read_u32(self.sector_addr(sector) + 4).await
+    }
+
+    /**
+     * Generation higher than that of every sector. The highest generation is only lost
+     * if the power is cut while its sector is erased, and then no stamp was made with
+     * it: `format` erases that sector after the others have got a higher generation,
+     * and otherwise it only holds records copied by a garbage collection, or none.
+     */
+    async fn next_generation(&mut self) -> Result<u32, RecordError<S::Error>> {
+        let mut highest = 0;
+        for sector in 0..self.sectors {
+            let generation = self.generation(sector).await?;
+            if generation != ERASED {
+                highest = highest.max(generation);
+            }
+        }
+        Ok(highest + 1)
+    }
+
+    async fn erase_sector(&mut self, sector: u32) -> Result<(), RecordError<S::Error>> {
+        let count = self.erase_count(sector).await?;
+        let generation = self.next_generation().await?;
+        let addr = self.sector_addr(sector);
+        M::erase(&mut self.flash, addr, addr + Self::SECTOR_SIZE)
+            .await
+            .map_err(RecordError::Flash)?;
+        let mut header = [0u8; 8];
+        header[..4].copy_from_slice(&(count + 1).to_le_bytes());
+        header[4..].copy_from_slice(&generation.to_le_bytes());
+        M::write(&mut self.flash, addr, &header)
+            .await
+            .map_err(RecordError::Flash)
+    }
+
+    async fn open_sector(&mut self, sector: u32, seq: u32) -> Result<(), RecordError<S::Error>> {
+        let addr = self.sector_addr(sector);
+        if !self.is_blank(sector, HEADER_SIZE).await? {
+            self.erase_sector(sector).await?;
+        } else if self.generation(sector).await? == ERASED {
+            // never erased by the store, or the power was cut right after the erase
+            let generation = self.next_generation().await?;
+            M::write(&mut self.flash, addr + 4, &generation.to_le_bytes())
+                .await
+                .map_err(RecordError::Flash)?;
+        }
+        // the magic is written last, so a sector with the magic has a whole sequence number
+        M::write(&mut self.flash, addr + 12, &seq.to_le_bytes())
+            .await
+            .map_err(RecordError::Flash)?;
+        M::write(&mut self.flash, addr + 8, &SECTOR_MAGIC.to_le_bytes())
+            .await
+            .map_err(RecordError::Flash)?;
+
+        self.head = sector;
+        self.head_seq = seq;
+        self.used += 1;
+        self.write_pos = HEADER_SIZE;
+        Ok(())
+    }
+
+    /**
+     * Takes the next erased sector into use, but never the last one, which is kept
+     * for garbage collection.
+     */
+    async fn open_next(&mut self) -> Result<(), RecordError<S::Error>> {
+        if self.sectors - self.used < 2 {
+            return Err(RecordError::Full);
+        }
+        if self.used == 0 {
+            return self.open_sector(0, 0).await;
+        }
+        let next = (self.head + 1) % self.sectors;
+        self.open_sector(next, self.head_seq.wrapping_add(1)).await
+    }
+
+    /**
+     * Reads the record at `pos` in the sector. Returns `None` at the end of the
+     * records, or if the header is broken, in which case nothing more is read from
+     * the sector.
+     */
+    async fn slot(
+        &mut self,
+        sector: u32,
+        pos: u32,
+    ) -> Result<Option<Record>, RecordError<S::Error>> {
+        if pos + HEADER_SIZE > Self::SECTOR_SIZE {
+            return Ok(None);
+        }
+        let addr = self.sector_addr(sector) + pos;
+        let mut header = [0u8; 16];
+        M::read(&mut self.flash, addr, &mut header)
+            .await
+            .map_err(RecordError::Flash)?;
+
+        let word =
+            |i: usize| u32::from_le_bytes([header[i], header[i + 1], header[i + 2], header[i + 3]]);
+        let key = word(0);
+        let len = u16::from_le_bytes([header[4], header[5]]);
+        let flags = u16::from_le_bytes([header[6], header[7]]);
+        let txn = word(8);
+        let crc = word(12);
+        if key == ERASED || pos + record_size(len as usize) > Self::SECTOR_SIZE {
+            return Ok(None);
+        }
+        Ok(Some(Record {
+            key,
+            len,
+            flags,
+            txn,
+            crc,
+            addr: addr + HEADER_SIZE,
+        }))
+    }
+
+    /**
+     * Calls `f` for every record, from the oldest to the newest.
+     */
+    async fn scan(&mut self, mut f: impl FnMut(&Record)) -> Result<(), RecordError<S::Error>> {
+        for k in 0..self.used {
+            let sector = (self.oldest() + k) % self.sectors;
+            let mut pos = HEADER_SIZE;
+            while let Some(record) = self.slot(sector, pos).await? {
+                f(&record);
+                pos += record_size(record.len as usize);
+            }
+        }
+        Ok(())
+    }
+
+    async fn write_record_header(
+        &mut self,
+        addr: u32,
+        key: u32,
+        len: u16,
+        flags: u16,
+        txn: u32,
+        crc: u32,
+    ) -> Result<(), RecordError<S::Error>> {
+        let mut header = [0xffu8; 16];
+        header[0..4].copy_from_slice(&key.to_le_bytes());
+        header[4..6].copy_from_slice(&len.to_le_bytes());
+        header[6..8].copy_from_slice(&flags.to_le_bytes());
+        header[8..12].copy_from_slice(&txn.to_le_bytes());
+        header[12..16].copy_from_slice(&crc.to_le_bytes());
+        M::write(&mut self.flash, addr, &header)
+            .await
+            .map_err(RecordError::Flash)
+    }
+
+    /**
+     * Checks if records with the given payload lengths, followed by a commit record,
+     * fit in the newest sector and the erased sectors, except the last one.
+     */
+    fn fits(&self, lens: impl Iterator<Item = usize>) -> bool {
+        let (mut pos, mut free) = if self.used == 0 {
+            (Self::SECTOR_SIZE, self.sectors - 1)
+        } else {
+            (self.write_pos, self.sectors - self.used - 1)
+        };
+        let records = lens.map(|len| (record_size(len), HEADER_SIZE));
+        for (size, tail) in records.chain(iter::once((HEADER_SIZE, 0))) {
+            if pos + size + tail > Self::SECTOR_SIZE {
+                if free == 0 {
+                    return false;
+                }
+                free -= 1;
+                pos = HEADER_SIZE;
+            }
+            pos += size;
+        }
+        true
+    }
+
+    /**
+     * Makes room for a transaction with the given payload lengths. Erased sectors are
+     * taken into use until only the last one is left, and then the oldest sectors
+     * are garbage collected.
+     */
+    async fn reserve(
+        &mut self,
+        lens: impl Iterator<Item = usize> + Clone,
+    ) -> Result<(), RecordError<S::Error>> {
+        for _ in 0..2 * self.sectors {
+            if self.fits(lens.clone()) {
+                return Ok(());
+            }
+            if self.used == 0 {
+                break;
+            }
+            if self.sectors - self.used >= 2 {
+                self.open_next().await?;
+            } else {
+                self.collect().await?;
+            }
+        }
+        Err(RecordError::Full)
+    }
+
+    /**
+     * Takes the last erased sector into use, copies the current records from the
+     * oldest sector into it, and erases the oldest sector.
+     */
+    async fn collect(&mut self) -> Result<(), RecordError<S::Error>> {
+        let next = (self.head + 1) % self.sectors;
+        self.open_sector(next, self.head_seq.wrapping_add(1))
+            .await?;
+
+        self.last_txn = self.last_txn.wrapping_add(1);
+        let txn = self.last_txn;
+        let oldest = self.oldest();
+        let mut pos = HEADER_SIZE;
+        while let Some(record) = self.slot(oldest, pos).await? {
+            if record.key != COMMIT_KEY && self.find(record.key).await? == Some(record) {
+                self.copy(&record, txn).await?;
+            }
+            pos += record_size(record.len as usize);
+        }
+        self.commit(Transaction {
+            id: txn,
+            remaining: HEADER_SIZE,
+        })
+        .await?;
+
+        self.erase_sector(oldest).await?;
+        self.used -= 1;
+        Ok(())
+    }
+
+    /**
+     * Finishes a garbage collection that was interrupted, which is when all sectors
+     * are in use. The newest sector only holds the copied records, so if they were
+     * committed the oldest sector is erased, and otherwise the newest sector is. A
+     * commit record only counts when its transaction id matches the copied records,
+     * as the power may have been cut after its key was written but before its id.
+     */
+    async fn finish_collect(&mut self) -> Result<(), RecordError<S::Error>> {
+        let mut pos = HEADER_SIZE;
+        let mut txn = None;
+        let mut committed = false;
+        while let Some(record) = self.slot(self.head, pos).await? {
+            if record.key == COMMIT_KEY {
+                committed |= txn.is_none_or(|txn| txn == record.txn);
+            } else {
+                txn.get_or_insert(record.txn);
+            }
+            pos += record_size(record.len as usize);
+        }
+
+        if committed {
+            let oldest = self.oldest();
+            self.erase_sector(oldest).await?;
+        } else {
+            self.erase_sector(self.head).await?;
+            self.head = (self.head + self.sectors - 1) % self.sectors;
+            self.head_seq = self.head_seq.wrapping_sub(1);
+        }
+        self.used -= 1;
+        Ok(())
+    }
+
+    async fn copy(&mut self, record: &Record, txn: u32) -> Result<(), RecordError<S::Error>> {
+        let size = record_size(record.len as usize);
+        if self.write_pos + size + HEADER_SIZE > Self::SECTOR_SIZE {
+            return Err(RecordError::Full);
+        }
+        let addr = self.sector_addr(self.head) + self.write_pos;
+        self.write_pos += size;
+        self.write_record_header(addr, record.key, record.len, record.flags, txn, record.crc)
+            .await?;
+
+        let mut block = [0u8; 16];
+        for pos in (0..size - HEADER_SIZE).step_by(16) {
+            M::read(&mut self.flash, record.addr + pos, &mut block)
+                .await
+                .map_err(RecordError::Flash)?;
+            M::write(&mut self.flash, addr + HEADER_SIZE + pos, &block)
+                .await
+                .map_err(RecordError::Flash)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{MemError, MemStorage};
+    use embassy_futures::block_on;
+
+    type Mem = MemStorage<{ 3 * 4096 }>;
+
+    const KEYS: usize = 3;
+    const MAX_LEN: usize = 640;
+
+    fn mount(mem: &mut Mem) -> RecordStore<&mut Mem> {
+        let mut store = RecordStore::new(mem, StorageRegion::new(0, 3 * 4096)).unwrap();
+        block_on(store.mount()).unwrap();
+        store
+    }
+
+    /**
+     * Value number `version` of the key, starting with the version, and with a length
+     * that changes between versions.
+     */
+    fn value(key: u32, version: u32, out: &mut [u8; MAX_LEN]) -> usize {
+        let len = 4 + ((version * 97 + key * 13) as usize % (MAX_LEN - 4));
+        out[..4].copy_from_slice(&version.to_le_bytes());
+        for (i, byte) in out[4..len].iter_mut().enumerate() {
+            *byte = (key * 31 + version * 7 + i as u32) as u8;
+        }
+        len
+    }
+
+    /**
+     * Version of the current value of the key, after checking that the value is whole.
+     */
+    fn stored_version(store: &mut RecordStore<&mut Mem>, key: u32) -> Option<u32> {
+        let record = block_on(store.find(key)).unwrap()?;
+        assert!(block_on(store.verify(&record)).unwrap());
+        let len = record.len as usize;
+        let mut stored = [0u8; MAX_LEN];
+        block_on(store.read(&record, 0, &mut stored[..len.next_multiple_of(16)])).unwrap();
+        let version = u32::from_le_bytes([stored[0], stored[1], stored[2], stored[3]]);
+        let mut expected = [0u8; MAX_LEN];
+        assert_eq!(value(key, version, &mut expected), len);
+        assert_eq!(stored[..len], expected[..len]);
+        Some(version)
+    }
+
+    /**
+     * Writes the keys in one transaction, like the wifi ssid and password.
+     */
+    fn append_all(
+        store: &mut RecordStore<&mut Mem>,
+        keys: &[u32],
+        version: u32,
+    ) -> Result<(), RecordError<MemError>> {
+        let mut bytes = [[0u8; MAX_LEN]; KEYS];
+        let mut lens = [0; KEYS];
+        for (i, key) in keys.iter().enumerate() {
+            lens[i] = value(*key, version, &mut bytes[i]);
+        }
+        let mut txn = block_on(store.begin_txn(lens[..keys.len()].iter().copied()))?;
+        for (i, key) in keys.iter().enumerate() {
+            let mut writer = block_on(store.begin(&mut txn, *key, lens[i], 0))?;
+            block_on(store.write(&mut writer, &bytes[i][..lens[i]]))?;
+            block_on(store.finish(writer))?;
+        }
+        block_on(store.commit(txn))
+    }
+
+    /**
+     * Writes a new version of the keys with the power cut after 0, 1, 2... bytes, until
+     * the write is done, and checks after each cut that the store mounts with either
+     * the old or the new values of the keys, and the other keys unchanged.
+     */
+    fn write_with_cuts(
+        mem: &mut Mem,
+        versions: &mut [Option<u32>; KEYS],
+        keys: &[u32],
+        version: u32,
+    ) {
+        for cut in 0.. {
+            let mut copy = Mem::new();
+            copy.data = mem.data;
+            copy.cut_power_after(cut);
+            let done = append_all(&mut mount(&mut copy), keys, version).is_ok();
+            copy.restore_power();
+
+            let mut store = mount(&mut copy);
+            let found: [Option<u32>; KEYS] =
+                core::array::from_fn(|key| stored_version(&mut store, key as u32));
+            let new = keys.iter().all(|key| found[*key as usize] == Some(version));
+            for key in 0..KEYS {
+                if !new || !keys.contains(&(key as u32)) {
+                    assert_eq!(
+                        found[key], versions[key],
+                        "key {key} after a cut at {cut} bytes"
+                    );
+                }
+            }
+            if done {
+                assert!(new, "version {version} is not stored");
+                mem.data = copy.data;
+                for key in keys {
+                    versions[*key as usize] = Some(version);
+                }
+                return;
+            }
+        }
+    }
+
+    #[test]
+    fn power_cut_keeps_committed_values() {
+        let mut mem = Mem::new();
+        let mut versions = [None; KEYS];
+        for version in 0..100 {
+            // key 0 is rarely written, so garbage collection has to copy it
+            let key = if version % 50 == 0 {
+                0
+            } else {
+                1 + version % 2
+            };
+            write_with_cuts(&mut mem, &mut versions, &[key], version);
+        }
+
+        // the values have been moved by garbage collection several times
+        let mut store = mount(&mut mem);
+        let erased: u32 = (0..3)
+            .map(|sector| block_on(store.erase_count(sector)).unwrap())
+            .sum();
+        assert!(erased >= 3, "{erased}");
+    }
+
+    /**
+     * Writes a value of the key in a transaction of its own, and adds the stamp of the
+     * record to `stamps` as soon as it is made.
+     */
+    fn append_stamped(
+        store: &mut RecordStore<&mut Mem>,
+        key: u32,
+        version: u32,
+        stamps: &mut [[u8; 12]],
+        count: &mut usize,
+    ) -> Result<(), RecordError<MemError>> {
+        let mut bytes = [0u8; MAX_LEN];
+        let len = value(key, version, &mut bytes);
+        let mut txn = block_on(store.begin_txn(iter::once(len)))?;
+        let mut writer = block_on(store.begin(&mut txn, key, len, 0))?;
+        stamps[*count] = writer.stamp();
+        *count += 1;
+        block_on(store.write(&mut writer, &bytes[..len]))?;
+        block_on(store.finish(writer))?;
+        block_on(store.commit(txn))
+    }
+
+    #[test]
+    fn power_cut_does_not_repeat_stamps() {
+        const ROUNDS: u32 = 600;
+        let mut mem = Mem::new();
+        let mut stamps = [[0u8; 12]; ROUNDS as usize];
+        let mut count = 0;
+        for round in 0..ROUNDS {
+            // the power is cut at a different point of every round, and every 20th
+            // round formats the store, which is sometimes cut right after an erase
+            let format = round % 20 == 19;
+            mem.cut_power_after(if format {
+                (round / 20 * 7 % 30) as usize
+            } else {
+                (round * 89 % 1500) as usize
+            });
+            let mut store = RecordStore::new(&mut mem, StorageRegion::new(0, 3 * 4096)).unwrap();
+            let mut run = || {
+                match block_on(store.mount()) {
+                    Err(RecordError::Corrupt) => block_on(store.format())?,
+                    result => result?,
+                }
+                if format {
+                    block_on(store.format())?;
+                }
+                append_stamped(
+                    &mut store,
+                    round % KEYS as u32,
+                    round,
+                    &mut stamps,
+                    &mut count,
+                )
+            };
+            let _ = run();
+            mem.restore_power();
+        }
+
+        assert!(count > ROUNDS as usize / 2, "{count}");
+        for (i, stamp) in stamps[..count].iter().enumerate() {
+            assert!(!stamps[..i].contains(stamp), "stamp {i} is repeated");
+        }
+    }
+
+    #[test]
+    fn power_cut_keeps_transaction_whole() {
+        let mut mem = Mem::new();
+        let mut versions = [None; KEYS];
+        for version in 0..20 {
+            write_with_cuts(&mut mem, &mut versions, &[0, 1], version);
+            write_with_cuts(&mut mem, &mut versions, &[2], version);
+        }
+    }
+}
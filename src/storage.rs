@@ -0,0 +1,287 @@
+use core::future::Future;
+use embedded_storage::nor_flash::{
+    ErrorType, NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash,
+};
+use embedded_storage::{ReadStorage, Storage};
+use embedded_storage_async::nor_flash::{
+    NorFlash as AsyncNorFlash, ReadNorFlash as AsyncReadNorFlash,
+};
+
+/**
+ * The part of the storage that is used for config, given as an offset and a length
+ * in bytes.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StorageRegion {
+    pub offset: u32,
+    pub len: u32,
+}
+
+impl StorageRegion {
+    pub const fn new(offset: u32, len: u32) -> Self {
+        Self { offset, len }
+    }
+
+    /**
+     * First offset after the region.
+     */
+    pub const fn end(&self) -> u64 {
+        self.offset as u64 + self.len as u64
+    }
+}
+
+/**
+ * How the storage is accessed, so the record store and the menu are written once for
+ * both blocking and async storage. `Blocking` is for `embedded_storage::nor_flash::NorFlash`,
+ * where the futures are ready at once, and `Async` is for
+ * `embedded_storage_async::nor_flash::NorFlash`, where the futures wait for the flash
+ * and let other tasks run meanwhile.
+ */
+pub trait Access<S: ErrorType> {
+    const WRITE_SIZE: usize;
+    const ERASE_SIZE: usize;
+
+    fn capacity(flash: &S) -> usize;
+
+    fn read(
+        flash: &mut S,
+        offset: u32,
+        bytes: &mut [u8],
+    ) -> impl Future<Output = Result<(), S::Error>>;
+
+    fn write(
+        flash: &mut S,
+        offset: u32,
+        bytes: &[u8],
+    ) -> impl Future<Output = Result<(), S::Error>>;
+
+    fn erase(flash: &mut S, from: u32, to: u32) -> impl Future<Output = Result<(), S::Error>>;
+}
+
+/** Access to blocking storage. */
+#[derive(Debug, Clone, Copy)]
+pub struct Blocking;
+
+/** Access to async storage. */
+#[derive(Debug, Clone, Copy)]
+pub struct Async;
+
+impl<S: NorFlash> Access<S> for Blocking {
+    const WRITE_SIZE: usize = S::WRITE_SIZE;
+    const ERASE_SIZE: usize = S::ERASE_SIZE;
+
+    fn capacity(flash: &S) -> usize {
+        flash.capacity()
+    }
+
+    async fn read(flash: &mut S, offset: u32, bytes: &mut [u8]) -> Result<(), S::Error> {
+        flash.read(offset, bytes)
+    }
+
+    async fn write(flash: &mut S, offset: u32, bytes: &[u8]) -> Result<(), S::Error> {
+        flash.write(offset, bytes)
+    }
+
+    async fn erase(flash: &mut S, from: u32, to: u32) -> Result<(), S::Error> {
+        flash.erase(from, to)
+    }
+}
+
+impl<S: AsyncNorFlash> Access<S> for Async {
+    const WRITE_SIZE: usize = S::WRITE_SIZE;
+    const ERASE_SIZE: usize = S::ERASE_SIZE;
+
+    fn capacity(flash: &S) -> usize {
+        flash.capacity()
+    }
+
+    async fn read(flash: &mut S, offset: u32, bytes: &mut [u8]) -> Result<(), S::Error> {
+        flash.read(offset, bytes).await
+    }
+
+    async fn write(flash: &mut S, offset: u32, bytes: &[u8]) -> Result<(), S::Error> {
+        flash.write(offset, bytes).await
+    }
+
+    async fn erase(flash: &mut S, from: u32, to: u32) -> Result<(), S::Error> {
+        flash.erase(from, to).await
+    }
+}
+
+/**
+ * Storage backed by a RAM buffer, which is initialized as erased flash (0xff).
+ * Useful for testing the config on a host, or for config that should not
+ * survive a reset. Power loss can be simulated with `cut_power_after`. It implements
+ * both the blocking and the async flash traits.
+ */
+pub struct MemStorage<const N: usize> {
+    pub data: [u8; N],
+    power_left: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemError {
+    OutOfBounds,
+    /** The simulated power was cut, see `MemStorage::cut_power_after`. */
+    PowerCut,
+}
+
+impl NorFlashError for MemError {
+    fn kind(&self) -> NorFlashErrorKind {
+        match self {
+            MemError::OutOfBounds => NorFlashErrorKind::OutOfBounds,
+            MemError::PowerCut => NorFlashErrorKind::Other,
+        }
+    }
+}
+
+impl<const N: usize> MemStorage<N> {
+    pub const fn new() -> Self {
+        Self {
+            data: [0xff; N],
+            power_left: None,
+        }
+    }
+
+    /**
+     * Cuts the power after `bytes` more bytes are written, where an erase counts as one
+     * byte. The write that crosses the limit is only done partly, and all writes and
+     * erases after it fail with `MemError::PowerCut`, until `restore_power` is called.
+     */
+    pub fn cut_power_after(&mut self, bytes: usize) {
+        self.power_left = Some(bytes);
+    }
+
+    pub fn restore_power(&mut self) {
+        self.power_left = None;
+    }
+
+    fn range(offset: u32, len: usize) -> Result<core::ops::Range<usize>, MemError> {
+        let start = offset as usize;
+        let end = start.checked_add(len).ok_or(MemError::OutOfBounds)?;
+        if end > N {
+            return Err(MemError::OutOfBounds);
+        }
+        Ok(start..end)
+    }
+
+    /**
+     * Number of the `len` bytes that can be written before the power is cut.
+     */
+    fn powered(&mut self, len: usize) -> usize {
+        match &mut self.power_left {
+            None => len,
+            Some(left) => {
+                let n = len.min(*left);
+                *left -= n;
+                n
+            }
+        }
+    }
+
+    fn program(
+        &mut self,
+        offset: u32,
+        bytes: &[u8],
+        op: impl Fn(&mut u8, u8),
+    ) -> Result<(), MemError> {
+        let range = Self::range(offset, bytes.len())?;
+        let n = self.powered(bytes.len());
+        for (old, new) in self.data[range].iter_mut().zip(&bytes[..n]) {
+            op(old, *new);
+        }
+        if n < bytes.len() {
+            return Err(MemError::PowerCut);
+        }
+        Ok(())
+    }
+}
+
+impl<const N: usize> Default for MemStorage<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> ReadStorage for MemStorage<N> {
+    type Error = MemError;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        let range = Self::range(offset, bytes.len())?;
+        bytes.copy_from_slice(&self.data[range]);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        N
+    }
+}
+
+impl<const N: usize> Storage for MemStorage<N> {
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.program(offset, bytes, |old, new| *old = new)
+    }
+}
+
+impl<const N: usize> ErrorType for MemStorage<N> {
+    type Error = MemError;
+}
+
+impl<const N: usize> ReadNorFlash for MemStorage<N> {
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        ReadStorage::read(self, offset, bytes)
+    }
+
+    fn capacity(&self) -> usize {
+        N
+    }
+}
+
+/**
+ * Behaves like NOR flash with 4 KiB sectors, so writing can only clear bits, and
+ * erasing sets them again.
+ */
+impl<const N: usize> NorFlash for MemStorage<N> {
+    const WRITE_SIZE: usize = 4;
+    const ERASE_SIZE: usize = 4096;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        let range = Self::range(from, (to - from) as usize)?;
+        if self.powered(1) == 0 {
+            return Err(MemError::PowerCut);
+        }
+        self.data[range].fill(0xff);
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.program(offset, bytes, |old, new| *old &= new)
+    }
+}
+
+impl<const N: usize> AsyncReadNorFlash for MemStorage<N> {
+    const READ_SIZE: usize = 1;
+
+    async fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        ReadStorage::read(self, offset, bytes)
+    }
+
+    fn capacity(&self) -> usize {
+        N
+    }
+}
+
+impl<const N: usize> AsyncNorFlash for MemStorage<N> {
+    const WRITE_SIZE: usize = 4;
+    const ERASE_SIZE: usize = 4096;
+
+    async fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        NorFlash::erase(self, from, to)
+    }
+
+    async fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        NorFlash::write(self, offset, bytes)
+    }
+}
@@ -0,0 +1,504 @@
+use core::fmt;
+use core::net::Ipv4Addr;
+
+/**
+ * Longest text of a typed value. `f32` values are written out in full, and a negative
+ * subnormal like `-1e-45` is 48 characters.
+ */
+pub const MAX_TEXT_LEN: usize = 48;
+
+/**
+ * Kind of value an entry holds. Values are stored as the text entered, and checked
+ * against the kind when stored, so the typed getters of `ConfigMenu` only have to parse
+ * them.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EntryKind<'a> {
+    /** Any ASCII text. */
+    Text,
+    /** Unsigned integer, within the range (inclusive). */
+    U32 { min: u32, max: u32 },
+    /** Signed integer, within the range (inclusive). */
+    I32 { min: i32, max: i32 },
+    /** `true`/`false`, `yes`/`no`, `on`/`off` or `1`/`0`. */
+    Bool,
+    /** One of the choices, read as the index of the choice. */
+    Choice(&'a [&'a str]),
+    /** IPv4 address, like `192.168.1.1`. */
+    Ipv4,
+    /** Floating point number, within the range (inclusive). */
+    F32 { min: f32, max: f32 },
+    /** Binary value, entered in the menu as hex or base64. See `ConfigEntry::bytes`. */
+    Bytes,
+}
+
+impl EntryKind<'_> {
+    /**
+     * Checks that the text is a value of the kind, and tells why if it is not.
+     */
+    pub fn check(&self, text: &str) -> Result<(), &'static str> {
+        match *self {
+            EntryKind::Text => Ok(()),
+            EntryKind::U32 { min, max } => {
+                let value = text.parse::<u32>().map_err(|_| "not a number")?;
+                in_range(value, min, max)
+            }
+            EntryKind::I32 { min, max } => {
+                let value = text.parse::<i32>().map_err(|_| "not a number")?;
+                in_range(value, min, max)
+            }
+            EntryKind::Bool => parse_bool(text).map(|_| ()),
+            EntryKind::Choice(_) => self.choice(text).map(|_| ()),
+            EntryKind::Ipv4 => parse_ipv4(text).map(|_| ()),
+            EntryKind::F32 { min, max } => {
+                let value = text.parse::<f32>().map_err(|_| "not a number")?;
+                in_range(value, min, max)
+            }
+            EntryKind::Bytes => Err("binary entry"),
+        }
+    }
+
+    /**
+     * Index of the choice matching the text, ignoring case.
+     */
+    pub fn choice(&self, text: &str) -> Result<usize, &'static str> {
+        let EntryKind::Choice(choices) = self else {
+            return Err("not a choice");
+        };
+        choices
+            .iter()
+            .position(|choice| choice.eq_ignore_ascii_case(text))
+            .ok_or("not one of the choices")
+    }
+}
+
+/**
+ * Describes the kind for the menu, like `u32 1..=65535` or `one of: low|high`.
+ */
+impl fmt::Display for EntryKind<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EntryKind::Text => write!(f, "text"),
+            EntryKind::U32 { min, max } => write!(f, "u32 {}..={}", min, max),
+            EntryKind::I32 { min, max } => write!(f, "i32 {}..={}", min, max),
+            EntryKind::Bool => write!(f, "yes/no"),
+            EntryKind::Choice(choices) => {
+                write!(f, "one of: ")?;
+                for (i, choice) in choices.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, "|")?;
+                    }
+                    write!(f, "{}", choice)?;
+                }
+                Ok(())
+            }
+            EntryKind::Ipv4 => write!(f, "IPv4 address"),
+            EntryKind::F32 { min, max } => write!(f, "f32 {}..={}", min, max),
+            EntryKind::Bytes => write!(f, "bytes, as hex or base64:<data>"),
+        }
+    }
+}
+
+/**
+ * Constraint on the values of an entry, on top of its kind.
+ */
+#[derive(Debug, Clone, Copy)]
+pub enum Constraint<'a> {
+    /** The value has at least this many characters. */
+    MinLen(usize),
+    /** The value has at most this many characters. */
+    MaxLen(usize),
+    /** The value only has characters from the string. */
+    Chars(&'a str),
+    /**
+     * The value matches the pattern, where `?` is any character, `*` is any number of
+     * characters, `#` is a digit, `@` is a letter, and `\` makes the next character
+     * match itself. Other characters match themselves.
+     */
+    Pattern(&'a str),
+    /** Function that tells why a value is not valid. */
+    Custom(fn(&str) -> Result<(), &'static str>),
+}
+
+impl Constraint<'_> {
+    /**
+     * Checks the value, and tells why if it is not valid.
+     */
+    pub fn check(&self, text: &str) -> Result<(), &'static str> {
+        self.check_len(text.len())?;
+        self.check_part(text)?;
+        match *self {
+            Constraint::Pattern(pattern) if !matches(pattern.as_bytes(), text.as_bytes()) => {
+                Err("does not match the pattern")
+            }
+            Constraint::Custom(check) => check(text),
+            _ => Ok(()),
+        }
+    }
+
+    /**
+     * Checks the length of a value.
+     */
+    pub fn check_len(&self, len: usize) -> Result<(), &'static str> {
+        match *self {
+            Constraint::MinLen(min) if len < min => Err("too short"),
+            Constraint::MaxLen(max) if len > max => Err("too long"),
+            _ => Ok(()),
+        }
+    }
+
+    /**
+     * Checks a part of a value that is written in parts. Only the characters can be
+     * checked like this.
+     */
+    pub fn check_part(&self, part: &str) -> Result<(), &'static str> {
+        match *self {
+            Constraint::Chars(chars) if !part.chars().all(|c| chars.contains(c)) => {
+                Err("has characters that are not allowed")
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /**
+     * True if the constraint can only be checked on the whole value.
+     */
+    pub fn needs_whole_value(&self) -> bool {
+        matches!(self, Constraint::Pattern(_) | Constraint::Custom(_))
+    }
+}
+
+/**
+ * Matches the text against a pattern of `Constraint::Pattern`. When a character does not
+ * match, only the last `*` is tried again, matching one more character, since the
+ * earlier ones can not make the rest match. This takes at most the length of the text
+ * times the length of the pattern steps.
+ */
+fn matches(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // the pattern after the last `*`, and where in the text it is tried
+    let mut star = None;
+    while t < text.len() {
+        if pattern.get(p) == Some(&b'*') {
+            p += 1;
+            star = Some((p, t));
+        } else if let Some(len) = match_one(&pattern[p..], text[t]) {
+            p += len;
+            t += 1;
+        } else if let Some((star_p, star_t)) = star {
+            p = star_p;
+            t = star_t + 1;
+            star = Some((star_p, t));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == b'*')
+}
+
+/**
+ * Matches the character against the start of the pattern, which is not `*`, and gives
+ * the length of the part of the pattern that matched it.
+ */
+fn match_one(pattern: &[u8], c: u8) -> Option<usize> {
+    let (ok, len) = match pattern {
+        [] => return None,
+        [b'\\', p, ..] => (*p == c, 2),
+        [b'?', ..] => (true, 1),
+        [b'#', ..] => (c.is_ascii_digit(), 1),
+        [b'@', ..] => (c.is_ascii_alphabetic(), 1),
+        [p, ..] => (*p == c, 1),
+    };
+    ok.then_some(len)
+}
+
+pub fn parse_bool(text: &str) -> Result<bool, &'static str> {
+    const TRUE: [&str; 4] = ["true", "yes", "on", "1"];
+    const FALSE: [&str; 4] = ["false", "no", "off", "0"];
+    if TRUE.iter().any(|word| word.eq_ignore_ascii_case(text)) {
+        Ok(true)
+    } else if FALSE.iter().any(|word| word.eq_ignore_ascii_case(text)) {
+        Ok(false)
+    } else {
+        Err("not yes or no")
+    }
+}
+
+pub fn parse_ipv4(text: &str) -> Result<Ipv4Addr, &'static str> {
+    text.parse::<Ipv4Addr>().map_err(|_| "not an IPv4 address")
+}
+
+fn in_range<T: PartialOrd>(value: T, min: T, max: T) -> Result<(), &'static str> {
+    // written so NaN is out of range
+    if min <= value && value <= max {
+        Ok(())
+    } else {
+        Err("out of range")
+    }
+}
+
+/**
+ * Decodes hex, like `0a1b2c`, into the output, and gives the number of bytes.
+ */
+pub fn decode_hex(text: &str, output: &mut [u8]) -> Result<usize, &'static str> {
+    let text = text.as_bytes();
+    if !text.len().is_multiple_of(2) {
+        return Err("odd number of hex digits");
+    }
+    if text.len() / 2 > output.len() {
+        return Err("too long");
+    }
+    for (out, pair) in output.iter_mut().zip(text.chunks(2)) {
+        *out = (hex_digit(pair[0])? << 4) | hex_digit(pair[1])?;
+    }
+    Ok(text.len() / 2)
+}
+
+fn hex_digit(c: u8) -> Result<u8, &'static str> {
+    match c {
+        b'0'..=b'9' => Ok(c - b'0'),
+        b'a'..=b'f' => Ok(c - b'a' + 10),
+        b'A'..=b'F' => Ok(c - b'A' + 10),
+        _ => Err("not hex"),
+    }
+}
+
+/**
+ * Decodes standard base64, with padding, into the output, and gives the number of bytes.
+ */
+pub fn decode_base64(text: &str, output: &mut [u8]) -> Result<usize, &'static str> {
+    let text = text.as_bytes();
+    if !text.len().is_multiple_of(4) {
+        return Err("base64 length is not a multiple of 4");
+    }
+    let mut len = 0;
+    for (i, group) in text.chunks(4).enumerate() {
+        let last = i == text.len() / 4 - 1;
+        let pad = group.iter().rev().take_while(|c| **c == b'=').count();
+        if pad > 2 || (pad > 0 && !last) {
+            return Err("not base64");
+        }
+        let mut bits = 0u32;
+        for c in &group[..4 - pad] {
+            bits = (bits << 6) | base64_digit(*c)?;
+        }
+        bits <<= 6 * pad;
+        let n = 3 - pad;
+        if len + n > output.len() {
+            return Err("too long");
+        }
+        output[len..len + n].copy_from_slice(&bits.to_be_bytes()[1..1 + n]);
+        len += n;
+    }
+    Ok(len)
+}
+
+fn base64_digit(c: u8) -> Result<u32, &'static str> {
+    match c {
+        b'A'..=b'Z' => Ok((c - b'A') as u32),
+        b'a'..=b'z' => Ok((c - b'a' + 26) as u32),
+        b'0'..=b'9' => Ok((c - b'0' + 52) as u32),
+        b'+' => Ok(62),
+        b'/' => Ok(63),
+        _ => Err("not base64"),
+    }
+}
+
+/**
+ * Decodes a binary value entered in the menu, as hex, or as base64 after `base64:`.
+ */
+pub fn decode_bytes(text: &str, output: &mut [u8]) -> Result<usize, &'static str> {
+    match text.strip_prefix("base64:") {
+        Some(base64) => decode_base64(base64, output),
+        None => decode_hex(text.strip_prefix("hex:").unwrap_or(text), output),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_integer_ranges() {
+        let port = EntryKind::U32 { min: 1, max: 65535 };
+        assert_eq!(port.check("1"), Ok(()));
+        assert_eq!(port.check("65535"), Ok(()));
+        assert_eq!(port.check("0"), Err("out of range"));
+        assert_eq!(port.check("65536"), Err("out of range"));
+        assert_eq!(port.check("-1"), Err("not a number"));
+        assert_eq!(port.check(""), Err("not a number"));
+
+        let offset = EntryKind::I32 { min: -10, max: 10 };
+        assert_eq!(offset.check("-10"), Ok(()));
+        assert_eq!(offset.check("-11"), Err("out of range"));
+        assert_eq!(offset.check("1.5"), Err("not a number"));
+    }
+
+    #[test]
+    fn nan_is_out_of_any_range() {
+        let ratio = EntryKind::F32 { min: 0.0, max: 1.0 };
+        assert_eq!(ratio.check("0.5"), Ok(()));
+        assert_eq!(ratio.check("1.01"), Err("out of range"));
+        assert_eq!(ratio.check("NaN"), Err("out of range"));
+
+        let any = EntryKind::F32 {
+            min: f32::NEG_INFINITY,
+            max: f32::INFINITY,
+        };
+        assert_eq!(any.check("-inf"), Ok(()));
+        assert_eq!(any.check("NaN"), Err("out of range"));
+    }
+
+    #[test]
+    fn choices_ignore_case() {
+        let level = EntryKind::Choice(&["low", "High"]);
+        assert_eq!(level.choice("LOW"), Ok(0));
+        assert_eq!(level.choice("high"), Ok(1));
+        assert_eq!(level.check("medium"), Err("not one of the choices"));
+        assert_eq!(EntryKind::Bool.choice("low"), Err("not a choice"));
+    }
+
+    #[test]
+    fn parses_bools() {
+        for text in ["true", "Yes", "ON", "1"] {
+            assert_eq!(parse_bool(text), Ok(true), "{text}");
+        }
+        for text in ["false", "No", "OFF", "0"] {
+            assert_eq!(parse_bool(text), Ok(false), "{text}");
+        }
+        assert_eq!(parse_bool("y"), Err("not yes or no"));
+        assert_eq!(EntryKind::Bool.check(""), Err("not yes or no"));
+    }
+
+    #[test]
+    fn checks_other_kinds() {
+        assert_eq!(EntryKind::Ipv4.check("192.168.1.1"), Ok(()));
+        assert_eq!(
+            EntryKind::Ipv4.check("192.168.1"),
+            Err("not an IPv4 address")
+        );
+        assert_eq!(EntryKind::Text.check("anything"), Ok(()));
+        assert_eq!(EntryKind::Bytes.check("00"), Err("binary entry"));
+    }
+
+    #[test]
+    fn checks_constraints() {
+        assert_eq!(Constraint::MinLen(2).check("ab"), Ok(()));
+        assert_eq!(Constraint::MinLen(2).check("a"), Err("too short"));
+        assert_eq!(Constraint::MaxLen(2).check("abc"), Err("too long"));
+
+        let hex = Constraint::Chars("0123456789abcdef");
+        assert_eq!(hex.check("c0ffee"), Ok(()));
+        assert_eq!(
+            hex.check("coffee"),
+            Err("has characters that are not allowed")
+        );
+
+        let even = Constraint::Custom(|text| match text.len() % 2 {
+            0 => Ok(()),
+            _ => Err("odd length"),
+        });
+        assert_eq!(even.check("ab"), Ok(()));
+        assert_eq!(even.check("abc"), Err("odd length"));
+        assert!(even.needs_whole_value());
+        assert!(!hex.needs_whole_value());
+    }
+
+    fn pattern(pattern: &str, text: &str) -> bool {
+        match Constraint::Pattern(pattern).check(text) {
+            Ok(()) => true,
+            Err(reason) => {
+                assert_eq!(reason, "does not match the pattern");
+                false
+            }
+        }
+    }
+
+    #[test]
+    fn matches_patterns() {
+        assert!(pattern("abc", "abc"));
+        assert!(!pattern("abc", "abd"));
+        assert!(!pattern("abc", "ab"));
+        assert!(!pattern("abc", "abcd"));
+
+        assert!(pattern("a?c", "abc"));
+        assert!(!pattern("a?c", "ac"));
+
+        assert!(pattern("#.#.#", "1.2.3"));
+        assert!(!pattern("#.#.#", "1.x.3"));
+        assert!(pattern("@@-##", "AB-12"));
+        assert!(!pattern("@@-##", "A1-12"));
+
+        assert!(pattern("*", ""));
+        assert!(pattern("*", "anything"));
+        assert!(pattern("esp-*", "esp-"));
+        assert!(pattern("esp-*", "esp-c3"));
+        assert!(pattern("*.local", "device.local"));
+        assert!(!pattern("*.local", "device.localhost"));
+        assert!(pattern("a*b*c", "aXXbYYbZc"));
+        assert!(!pattern("a*b*c", "aXXcYYb"));
+        assert!(pattern("**a", "a"));
+
+        assert!(pattern(r"\*", "*"));
+        assert!(!pattern(r"\*", "x"));
+        assert!(pattern(r"\#\?", "#?"));
+        assert!(!pattern(r"\#", "1"));
+        assert!(pattern(r"a\\b", r"a\b"));
+    }
+
+    #[test]
+    fn decodes_hex() {
+        let mut out = [0; 4];
+        assert_eq!(decode_hex("0a1B2c", &mut out), Ok(3));
+        assert_eq!(out[..3], [0x0a, 0x1b, 0x2c]);
+        assert_eq!(decode_hex("", &mut out), Ok(0));
+        assert_eq!(decode_hex("0a1", &mut out), Err("odd number of hex digits"));
+        assert_eq!(decode_hex("0g", &mut out), Err("not hex"));
+        assert_eq!(decode_hex("0011223344", &mut out), Err("too long"));
+    }
+
+    #[test]
+    fn decodes_base64() {
+        let mut out = [0; 6];
+        assert_eq!(decode_base64("AAECAwQF", &mut out), Ok(6));
+        assert_eq!(out, [0, 1, 2, 3, 4, 5]);
+        assert_eq!(decode_base64("+/8=", &mut out), Ok(2));
+        assert_eq!(out[..2], [0xfb, 0xff]);
+        assert_eq!(decode_base64("AQ==", &mut out), Ok(1));
+        assert_eq!(out[0], 1);
+
+        assert_eq!(
+            decode_base64("AQ=", &mut out),
+            Err("base64 length is not a multiple of 4")
+        );
+        assert_eq!(decode_base64("AQ==AAAA", &mut out), Err("not base64"));
+        assert_eq!(decode_base64("A===", &mut out), Err("not base64"));
+        assert_eq!(decode_base64("A=AA", &mut out), Err("not base64"));
+        assert_eq!(decode_base64("AQ-_", &mut out), Err("not base64"));
+        assert_eq!(decode_base64("AAECAwQFBg==", &mut out), Err("too long"));
+    }
+
+    #[test]
+    fn decodes_bytes_by_prefix() {
+        let mut out = [0; 4];
+        assert_eq!(decode_bytes("0102", &mut out), Ok(2));
+        assert_eq!(out[..2], [1, 2]);
+        assert_eq!(decode_bytes("hex:0304", &mut out), Ok(2));
+        assert_eq!(out[..2], [3, 4]);
+        assert_eq!(decode_bytes("base64:BQY=", &mut out), Ok(2));
+        assert_eq!(out[..2], [5, 6]);
+        // without the prefix base64 is read as hex
+        assert_eq!(decode_bytes("BQY=", &mut out), Err("not hex"));
+        assert_eq!(decode_bytes("hex:BQY=", &mut out), Err("not hex"));
+    }
+
+    #[test]
+    fn stars_do_not_backtrack_exponentially() {
+        // each `*` could end at any of the 64 characters, and trying every combination
+        // of them would not finish
+        let text = [b'a'; 64];
+        let text = core::str::from_utf8(&text).unwrap();
+        assert!(!pattern("*a*a*a*a*a*a*a*a*a*a*b", text));
+        assert!(pattern("*a*a*a*a*a*a*a*a*a*a*", text));
+    }
+}